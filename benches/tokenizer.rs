@@ -0,0 +1,59 @@
+use criterion::{
+  criterion_group, criterion_main, BenchmarkId, Criterion, Throughput,
+};
+use html::parse::parse_html;
+
+const MEGABYTE: usize = 1 << 20;
+const SIZES: [usize; 4] = [1, 2, 4, 8];
+
+/// A slice of a typical page, repeated to build large inputs
+const CHUNK: &str = r#"
+<div class="card" data-id=42 @click="open()">
+  <!-- a short comment -->
+  <a href="/projects/html-templating" title='say "hi" &amp; bye'>
+    <img src=/resources/rust-icon.svg alt="Rust">
+    <h2> Completed Projects </h2>
+  </a>
+  <pre>  fn main() {
+    println!("hello");
+  }</pre>
+  <p>Text with <b>inline</b> <i>markup</i> and unicode: héllo wörld</p>
+  <textarea name="notes">a < b && c</textarea>
+</div>
+"#;
+
+fn page(size: usize) -> String {
+  CHUNK.repeat(size / CHUNK.len() + 1)
+}
+
+fn comment(size: usize) -> String {
+  format!("<!--{}-->", "- <!- -".repeat(size / 7 + 1))
+}
+
+fn script(size: usize) -> String {
+  format!("<script>{}</script>", "if (a</b) {} ".repeat(size / 13 + 1))
+}
+
+fn bench_input(c: &mut Criterion, group: &str, input: fn(usize) -> String) {
+  let mut group = c.benchmark_group(group);
+  group.sample_size(10);
+  for megabytes in SIZES {
+    let input = input(megabytes * MEGABYTE);
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_with_input(
+      BenchmarkId::from_parameter(format!("{}MB", megabytes)),
+      &input,
+      |b, input| b.iter(|| parse_html(input).unwrap()),
+    );
+  }
+  group.finish();
+}
+
+fn tokenizer(c: &mut Criterion) {
+  bench_input(c, "page", page);
+  bench_input(c, "long_comment", comment);
+  bench_input(c, "long_script", script);
+}
+
+criterion_group!(benches, tokenizer);
+criterion_main!(benches);
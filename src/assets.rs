@@ -0,0 +1,369 @@
+use std::{
+  collections::HashMap,
+  ffi::OsStr,
+  path::{Component, Path, PathBuf},
+};
+
+use crate::{diagnostic::json_string, parse::HtmlElement, trace::*, tree::*};
+
+/// Extensions of the files that are named by their contents
+const FINGERPRINTED: [&str; 15] = [
+  "css", "js", "mjs", "png", "jpg", "jpeg", "gif", "svg", "webp", "avif",
+  "ico", "woff", "woff2", "ttf", "otf",
+];
+
+/// 64 bit FNV-1a. Unlike the hasher of the standard library, it
+/// is stable between Rust versions, so names only change when
+/// contents do
+fn fingerprint(contents: &[u8]) -> u64 {
+  contents.iter().fold(0xcbf29ce484222325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// Whether the file at `path` is an asset that is named by its
+/// contents
+pub fn is_fingerprinted(path: &Path) -> bool {
+  path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+    FINGERPRINTED.contains(&ext.to_ascii_lowercase().as_str())
+  })
+}
+
+/// `path` with the hash of `contents` added to its file name, as
+/// in `styles/main-ad0a5132b4027392.css`
+pub fn fingerprinted_name(path: &Path, contents: &[u8]) -> PathBuf {
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  let hash = fingerprint(contents);
+  let name = match path.extension() {
+    Some(ext) => format!("{}-{:016x}.{}", stem, hash, ext.to_string_lossy()),
+    None => format!("{}-{:016x}", stem, hash),
+  };
+  path.with_file_name(name)
+}
+
+/// Removes `.` and resolves `..` in a relative path without
+/// reading the filesystem. Paths that leave their root have no
+/// normal form
+fn normalize(path: &Path) -> Option<PathBuf> {
+  let mut normal = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(part) => normal.push(part),
+      Component::ParentDir if !normal.pop() => return None,
+      _ => {},
+    }
+  }
+  Some(normal)
+}
+
+/// `path` with `/` between its components on every platform
+fn with_slashes(path: &Path) -> String {
+  path
+    .components()
+    .map(|component| component.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// Every file in `root` and below, relative to `root`
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  let path = root.join(dir);
+  let entries = std::fs::read_dir(&path)
+    .ctx(format!("reading directory {}", path.display()))?;
+  for entry in entries {
+    let entry = entry.ctx(format!("reading directory {}", path.display()))?;
+    let file = dir.join(entry.file_name());
+    if entry.file_type().is_ok_and(|ft| ft.is_dir()) {
+      list_files(root, &file, files)?;
+    } else {
+      files.push(file);
+    }
+  }
+  Ok(())
+}
+
+/// The content hashed names of the assets in a source folder,
+/// which pages and stylesheets are rewritten to use
+#[derive(Clone, Debug, Default)]
+pub struct Assets {
+  /// The source folder. References starting with `/` are
+  /// resolved from here
+  root: PathBuf,
+  /// Hashed paths by original path, both relative to `root`
+  names: HashMap<PathBuf, PathBuf>,
+}
+
+impl Assets {
+  /// Names every asset in `root` and below by its contents.
+  /// Stylesheets are named after their references are rewritten,
+  /// so they change along with the files they use
+  pub fn scan(root: impl AsRef<Path>) -> Result<Self> {
+    let mut assets = Self {
+      root: root.as_ref().to_path_buf(),
+      names: HashMap::new(),
+    };
+    let mut files = vec![];
+    list_files(&assets.root, Path::new(""), &mut files)?;
+    files.retain(|file| is_fingerprinted(file));
+    let (styles, others): (Vec<_>, Vec<_>) = files
+      .into_iter()
+      .partition(|file| file.extension() == Some(OsStr::new("css")));
+    for file in others {
+      let contents = assets.read(&assets.root.join(&file))?;
+      let hashed = fingerprinted_name(&file, &contents);
+      assets.names.insert(file, hashed);
+    }
+    // Stylesheets can import each other, so they are named again
+    // until no name changes, which takes one pass more than the
+    // longest chain of imports
+    for _ in 0..=styles.len() {
+      let mut changed = false;
+      for file in &styles {
+        let contents = assets.read(&assets.root.join(file))?;
+        let hashed = fingerprinted_name(file, &contents);
+        changed |=
+          assets.names.insert(file.clone(), hashed.clone()) != Some(hashed);
+      }
+      if !changed {
+        break;
+      }
+    }
+    Ok(assets)
+  }
+
+  /// The hashed file name of the asset at `path`, which is in the
+  /// source folder
+  pub fn hashed_name(&self, path: &Path) -> Option<&OsStr> {
+    let file = path.strip_prefix(&self.root).ok()?;
+    self.names.get(file)?.file_name()
+  }
+
+  /// The contents of the asset at `path` as they are written out,
+  /// with the references of stylesheets rewritten
+  pub fn read(&self, path: &Path) -> Result<Vec<u8>> {
+    let contents = std::fs::read(path)
+      .ctx(format!("opening file to read: {}", path.display()))?;
+    let file = match path.strip_prefix(&self.root) {
+      Ok(file) if file.extension() == Some(OsStr::new("css")) => file,
+      _ => return Ok(contents),
+    };
+    let css = String::from_utf8_lossy(&contents);
+    Ok(self.rewrite_css(&css, file).into_bytes())
+  }
+
+  /// `reference` from the file at `from` with the name of the
+  /// asset it points to replaced by the hashed one, keeping its
+  /// directories, query and fragment. External references and
+  /// ones to files that are not assets are left alone
+  fn resolve(&self, reference: &str, from: &Path) -> Option<String> {
+    if reference.starts_with("//") || reference.contains(':') {
+      return None;
+    }
+    let end = reference.find(['?', '#']).unwrap_or(reference.len());
+    let (path, suffix) = reference.split_at(end);
+    let target = match path.strip_prefix('/') {
+      Some(absolute) => normalize(Path::new(absolute))?,
+      None => normalize(&from.parent().unwrap_or(Path::new("")).join(path))?,
+    };
+    let hashed = self.names.get(&target)?.file_name()?.to_string_lossy();
+    let dir = path.rfind('/').map_or("", |i| &path[..=i]);
+    Some(format!("{}{}{}", dir, hashed, suffix))
+  }
+
+  /// Points the `url()`s and `@import`ed strings of a stylesheet
+  /// at `file`, relative to the root, at hashed assets
+  fn rewrite_css(&self, css: &str, file: &Path) -> String {
+    let css = self.rewrite_imports(css, file);
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css.as_str();
+    while let Some(start) = rest.find("url(") {
+      let (before, after) = rest.split_at(start + "url(".len());
+      output.push_str(before);
+      let Some(end) = after.find(')') else {
+        rest = after;
+        break;
+      };
+      let argument = &after[..end];
+      let quote = match argument.trim().chars().next() {
+        Some(c @ ('"' | '\'')) => Some(c),
+        _ => None,
+      };
+      let reference = match quote {
+        Some(quote) => argument.trim().trim_matches(quote),
+        None => argument.trim(),
+      };
+      match self.resolve(reference, file) {
+        Some(hashed) => {
+          let quote = quote.map(String::from).unwrap_or_default();
+          output.push_str(&format!("{}{}{}", quote, hashed, quote));
+        },
+        None => output.push_str(argument),
+      }
+      rest = &after[end..];
+    }
+    output.push_str(rest);
+    output
+  }
+
+  /// Points the imports of a stylesheet written as strings, as in
+  /// `@import "base.css"`, at hashed assets. Imports written as
+  /// `url()` are left to `rewrite_css`
+  fn rewrite_imports(&self, css: &str, file: &Path) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("@import") {
+      let (before, after) = rest.split_at(start + "@import".len());
+      output.push_str(before);
+      let space = after.len() - after.trim_start().len();
+      let (space, after) = after.split_at(space);
+      output.push_str(space);
+      rest = after;
+      let Some(quote @ ('"' | '\'')) = after.chars().next() else {
+        continue;
+      };
+      let Some(end) = after[1..].find(quote) else {
+        break;
+      };
+      let reference = &after[1..=end];
+      let reference = self
+        .resolve(reference, file)
+        .unwrap_or_else(|| reference.to_string());
+      output.push_str(&format!("{}{}{}", quote, reference, quote));
+      rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    output
+  }
+
+  /// Points every candidate of a `srcset`, as in
+  /// `a.png 1x, b.png 2x`, at hashed assets
+  fn rewrite_srcset(&self, srcset: &str, page: &Path) -> String {
+    let candidates: Vec<String> = srcset
+      .split(',')
+      .map(|candidate| {
+        let start = candidate.len() - candidate.trim_start().len();
+        let (space, rest) = candidate.split_at(start);
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (reference, descriptor) = rest.split_at(end);
+        match self.resolve(reference, page) {
+          Some(hashed) => format!("{}{}{}", space, hashed, descriptor),
+          None => candidate.to_string(),
+        }
+      })
+      .collect();
+    candidates.join(",")
+  }
+
+  /// Points the `href`, `src` and `srcset` attributes of a page
+  /// at `page`, which is in the source folder, at hashed assets,
+  /// along with the `url()`s of its `<style>` tags and `style`
+  /// attributes
+  pub fn rewrite_page(&self, nodes: &mut [Node<'_>], page: &Path) {
+    if let Ok(page) = page.strip_prefix(&self.root) {
+      self.rewrite_nodes(nodes, page);
+    }
+  }
+
+  fn rewrite_nodes(&self, nodes: &mut [Node<'_>], page: &Path) {
+    for node in nodes {
+      match node {
+        Node::Element(element) => {
+          self.rewrite_nodes(&mut element.children, page)
+        },
+        Node::Leaf(HtmlElement::Style { contents, .. }, _) => {
+          *contents = self.rewrite_css(contents, page).into();
+        },
+        _ => {},
+      }
+      let Some(attributes) = node.attributes_mut() else {
+        continue;
+      };
+      for (key, value) in attributes.iter_mut() {
+        let rewritten = match key.as_ref() {
+          "href" | "src" => self.resolve(value, page),
+          "srcset" => Some(self.rewrite_srcset(value, page)),
+          "style" => Some(self.rewrite_css(value, page)),
+          _ => None,
+        };
+        if let Some(rewritten) = rewritten {
+          *value = rewritten.into();
+        }
+      }
+    }
+  }
+
+  /// A JSON object from the path of each asset to its hashed path,
+  /// both relative to the output folder:
+  ///
+  /// ```json
+  /// {
+  ///   "styles/main.css": "styles/main-ad0a5132b4027392.css"
+  /// }
+  /// ```
+  pub fn manifest(&self) -> String {
+    let mut entries: Vec<String> = self
+      .names
+      .iter()
+      .map(|(file, hashed)| {
+        format!(
+          "  {}: {}",
+          json_string(&with_slashes(file)),
+          json_string(&with_slashes(hashed))
+        )
+      })
+      .collect();
+    if entries.is_empty() {
+      return "{}\n".to_string();
+    }
+    entries.sort();
+    format!("{{\n{}\n}}\n", entries.join(",\n"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse::ParseMode;
+
+  #[test]
+  fn rewrites_string_imports() {
+    let assets = Assets {
+      root: PathBuf::new(),
+      names: [("css/a.css", "css/a-1.css"), ("b.css", "b-2.css")]
+        .map(|(file, hashed)| (file.into(), hashed.into()))
+        .into(),
+    };
+    let css = "@import \"a.css\";\n@import '../b.css' screen;\n\
+      @import url(a.css);\n@import \"c.css\";\n@importa.css";
+    assert_eq!(
+      assets.rewrite_css(css, Path::new("css/main.css")),
+      "@import \"a-1.css\";\n@import '../b-2.css' screen;\n\
+       @import url(a-1.css);\n@import \"c.css\";\n@importa.css"
+    );
+  }
+
+  #[test]
+  fn rewrites_srcset_and_inline_styles() {
+    let assets = Assets {
+      root: PathBuf::new(),
+      names: [("img/a.png", "img/a-1.png"), ("img/b.png", "img/b-2.png")]
+        .map(|(file, hashed)| (file.into(), hashed.into()))
+        .into(),
+    };
+    let source = r#"<style>p { background: url("img/a.png") }</style><img srcset="img/a.png 1x, /img/b.png 2x, data:x 3x" style="mask: url(img/b.png)"/>"#;
+    let mut nodes = parse_document(source, ParseMode::Strict).unwrap();
+    assets.rewrite_page(&mut nodes, Path::new("index.html"));
+    let output = serialize(&nodes);
+    assert!(output.contains(r#"url("img/a-1.png")"#), "{}", output);
+    assert!(
+      output.contains(r#"srcset="img/a-1.png 1x, /img/b-2.png 2x, data:x 3x""#),
+      "{}",
+      output
+    );
+    assert!(
+      output.contains(r#"style="mask: url(img/b-2.png)""#),
+      "{}",
+      output
+    );
+  }
+}
@@ -1,354 +1,1565 @@
-use crate::trace::*;
-use std::{collections::HashMap, io::Write, path::Path, rc::Rc};
+use crate::{
+  assets::*, directives::directive_problem, lint::*, params::*, scope::*,
+  trace::*, transform::*, tree::*,
+};
+use std::{
+  borrow::Cow,
+  collections::{HashMap, HashSet},
+  io::Write,
+  path::Path,
+  rc::Rc,
+};
 
 use crate::parse::*;
 
-type Lexeme = HtmlElement;
+type Lexeme<'a> = HtmlElement<'a>;
 
+/// How deeply template usages may be nested inside of each other
 pub const RECURSION_LIMIT: usize = 256;
 
-#[derive(Debug, Clone)]
-pub struct Element {
-  attributes: Attributes,
-  child_span: Vec<Lexeme>,
-}
-pub type Templates = HashMap<String, Element>;
+pub type Templates<'a> = HashMap<String, Element<'a>>;
 
-fn parse_element<'a>(tail: &'a [Lexeme]) -> Option<(Element, &'a [Lexeme])> {
-  // Parse first element
-  let (_, attributes) = match tail.first() {
-    Some(lm) => match lm {
-      Lexeme::OpenTag {
-        attributes,
-        is_empty: true,
-        ..
-      } => {
-        return Some((
-          Element {
-            attributes: attributes.clone(),
-            child_span: vec![],
-          },
-          &tail[1..],
-        ))
-      },
-      Lexeme::OpenTag {
-        name,
-        attributes,
-        is_empty: false,
-      } => (name, attributes),
-      _ => return None,
-    },
-    None => return None,
+/// Marks a template definition as deliberately replacing any
+/// other definition of the same name, as in `<Card @override>`
+pub const OVERRIDE_MARKER: &str = "@override";
+
+/// Adds `template` to `templates` as `name`, failing if another
+/// template of the same name exists and exactly one of them is not
+/// marked with `OVERRIDE_MARKER`. This keeps the result
+/// independent of the order files are read in
+pub fn define_template<'a>(
+  templates: &mut Templates<'a>,
+  name: String,
+  template: Element<'a>,
+) -> Result<()> {
+  let Some(existing) = templates.get(&name) else {
+    templates.insert(name, template);
+    return Ok(());
   };
+  match (
+    existing.has_attribute(OVERRIDE_MARKER),
+    template.has_attribute(OVERRIDE_MARKER),
+  ) {
+    (true, false) => Ok(()),
+    (false, true) => {
+      templates.insert(name, template);
+      Ok(())
+    },
+    (overrides, _) => {
+      let mut error =
+        compile_error(format!("template `{}` is defined more than once", name))
+          .at(&template.span)
+          .note_at("first defined here", &existing.span);
+      error.backtrace.push(Note::new(if overrides {
+        "only one definition can be marked `@override`"
+      } else {
+        "mark one definition `@override` to replace the other"
+      }));
+      Err(error)
+    },
+  }
+}
 
-  let mut depth = 0;
-  let mut end = 1;
+/// The templates defined in one file, and the imports they can
+/// use
+pub struct TemplateFile<'a> {
+  pub templates: Templates<'a>,
+  /// Imports between the definitions, which apply to every
+  /// template in the file
+  pub imports: Vec<Import>,
+  pub warnings: Vec<Warning>,
+}
 
-  for lm in tail {
-    match lm {
-      Lexeme::OpenTag { is_empty, .. } => {
-        if !is_empty {
-          depth += 1;
-        }
-      },
-      Lexeme::CloseTag { .. } => {
-        depth -= 1;
-        if depth == 0 {
-          break;
-        }
+pub fn parse_templates(
+  source: &str,
+  mode: ParseMode,
+) -> Result<TemplateFile<'_>> {
+  parse_templates_from(source, mode, None)
+}
+
+/// Parses template definitions, naming `file` as their source
+pub fn parse_templates_from(
+  source: &str,
+  mode: ParseMode,
+  file: Option<Rc<Path>>,
+) -> Result<TemplateFile<'_>> {
+  let mut new_templates: Templates = Default::default();
+  let mut imports = vec![];
+  let mut warnings = vec![];
+  for node in parse_document_from(source, mode, file)? {
+    match node {
+      Node::Element(element) => {
+        declared_parameters(&element)?;
+        define_template(&mut new_templates, element.name.to_string(), element)?
       },
-      _ => {
-        if depth == 0 {
-          return None;
+      // Ignore comments and whitespace between definitions
+      Node::Leaf(Lexeme::Comment(_), _) => {},
+      Node::Leaf(Lexeme::Text(text), _) if text.trim().is_empty() => {},
+      Node::Leaf(lm, span) => {
+        if let Some(import) = Import::parse(&lm, &span)? {
+          imports.push(import);
+          continue;
         }
+        let message = format!(
+          "unexpected `{}` between template definitions",
+          lm.serialize().trim()
+        );
+        warnings
+          .push(Warning::new(Lint::UnexpectedRootNode, message).at(&span));
       },
     }
-    end += 1;
   }
-  Some((
-    Element {
-      attributes: attributes.clone(),
-      child_span: tail[1..end - 1].to_vec(),
-    },
-    &tail[end..],
-  ))
+  Ok(TemplateFile {
+    templates: new_templates,
+    imports,
+    warnings,
+  })
 }
 
-pub fn parse_templates(source: impl AsRef<str>) -> Result<Templates> {
-  let mut tail: &[Lexeme] = &parse_html(source.as_ref())?;
-  let mut new_templates: Templates = Default::default();
-  while let Some(lm) = tail.first() {
-    match lm {
-      Lexeme::OpenTag {
-        name,
-        is_empty: false,
-        ..
-      } => {
-        let (head, new_tail) = parse_element(tail)
-          .ctx(format!("at template definition {}", name))?;
-
-        new_templates.insert(name.clone(), head);
-        tail = new_tail;
-      },
-      // Ignore comments
-      Lexeme::Comment(_) => {
-        tail = &tail[1..];
-      },
-      _ => {
-        eprintln!("[WARNING] Unexpected root node: {}", lm.serialize());
-        tail = &tail[1..];
-      },
+pub fn parse_templates_file(
+  path: impl AsRef<Path>,
+  mode: ParseMode,
+) -> Result<TemplateFile<'static>> {
+  let file = read_file(path.as_ref()).ctx("opening templates file")?;
+  let parsed = parse_templates_from(&file, mode, Some(path.as_ref().into()))
+    .ctx(format!("in file {}", path.as_ref().display()))?;
+  Ok(TemplateFile {
+    templates: parsed
+      .templates
+      .into_iter()
+      .map(|(name, element)| (name, element.into_owned()))
+      .collect(),
+    imports: parsed.imports,
+    warnings: parsed.warnings,
+  })
+}
+
+/// Adds every `.html` file in `from` and its subfolders to
+/// `files`, with the namespace of the folder it is in
+fn list_template_folder(
+  from: &Path,
+  namespace: Option<String>,
+  files: &mut Vec<(Rc<Path>, Option<String>)>,
+) -> Result<()> {
+  for file in std::fs::read_dir(from)? {
+    let file = file.ctx(format!("reading directory {}", from.display()))?;
+    let path = file.path();
+    let last = path
+      .components()
+      .next_back()
+      .ctx("empty path encountered")?;
+    let ft = match file.file_type() {
+      Ok(ft) => ft,
+      Err(_) => continue,
+    };
+    if ft.is_dir() {
+      let folder = last.as_os_str().to_string_lossy();
+      let namespace = qualify(namespace.as_deref(), &folder);
+      list_template_folder(&from.join(last), Some(namespace), files)?;
+      continue;
+    }
+    let ext = match path.extension() {
+      Some(ext) => ext,
+      None => continue,
+    };
+
+    if ext != "html" {
+      continue;
     }
+    files.push((path.into(), namespace.clone()));
   }
-  Ok(new_templates)
+  Ok(())
 }
 
-pub fn parse_templates_file(path: impl AsRef<Path>) -> Result<Templates> {
-  let file = read_file(path.as_ref()).ctx(format!("opening templates file"))?;
-  parse_templates(file).ctx(format!("in file {}", path.as_ref().display()))
+/// Expanded fragments passed to a template usage, by name
+type Fragments<'a> = HashMap<String, Vec<Node<'a>>>;
+
+/// A template usage being expanded
+struct Usage<'u, 'a> {
+  element: &'u Element<'a>,
+  /// Attributes of the usage that are not parameters
+  forwarded: Attributes<'a>,
+  /// Whether the template spreads `forwarded` onto elements
+  /// marked `@...`. If not, the forwarded `class` and `style` are
+  /// still merged onto the template's root elements
+  spreads: bool,
 }
 
-fn expand_template(
-  base: &Element,
-  template: &Element,
-  output: &mut Vec<Lexeme>,
-) {
-  for element in &template.child_span {
-    match element {
-      Lexeme::OpenTag {
+/// Expands a usage of `template`, borrowing its nodes rather
+/// than copying them. The `<@children />` and `@name` placeholders
+/// are kept, to be spliced once the body is expanded. Returns the
+/// number of nodes created
+fn expand_template<'a>(
+  usage: &Usage<'_, 'a>,
+  template: &'a [Node<'_>],
+  is_root: bool,
+  output: &mut Vec<Node<'a>>,
+  warnings: &mut Vec<Warning>,
+) -> usize {
+  let base = usage.element;
+  let mut size = 0;
+  for node in template {
+    match node {
+      Node::Element(Element {
         name,
-        is_empty,
         attributes,
-      } => {
+        is_empty,
+        children,
+        span,
+      }) => {
         let mut new_attributes = HashMap::new();
 
         for (key, value) in attributes {
-          if let Some(at_key) = value.strip_prefix('@') {
+          if key == SPREAD_MARKER {
+            continue;
+          }
+          if let Some(at_key) = parameter_reference(value) {
             if let Some(at_value) = base.attributes.get(at_key) {
-              new_attributes.insert(at_key.to_string(), at_value.clone());
+              new_attributes.insert(Cow::Borrowed(&**key), at_value.clone());
+            } else {
+              let message = format!(
+                "`{}` uses `@{}`, which this usage does not set",
+                base.name, at_key
+              );
+              warnings
+                .push(Warning::new(Lint::MissingParameter, message).at(span));
             }
           } else {
-            new_attributes.insert(key.into(), value.into());
+            new_attributes.insert(Cow::Borrowed(&**key), Cow::Borrowed(value));
           }
         }
+        if attributes.contains_key(SPREAD_MARKER) {
+          merge_attributes(&mut new_attributes, &usage.forwarded);
+        } else if is_root && !usage.spreads {
+          let styling = usage
+            .forwarded
+            .iter()
+            .filter(|(key, _)| MERGED_ATTRIBUTES.contains(&key.as_ref()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+          merge_attributes(&mut new_attributes, &styling);
+        }
 
-        // for (key, value) in attributes {
-        //   // If value is non-null
-        //   if let Some(id) = value {
-        //     // If value is '@' prefixed
-        //     if let Some(variable_name) = id.strip_prefix('@') {
-        //       // If key exists in base
-        //       if let Some(new_value) =
-        // base.attributes.get(variable_name) {
-        //         new_attributes.insert(key.clone(),
-        // new_value.clone());       }
-        //       // Otherwise discard variable
-        //     }
-        //     // Insert non-null non-variable value
-        //     else {
-        //       new_attributes.insert(key.clone(), value.clone());
-        //     }
-        //   } else {
-        //     // Insert null non-variable value
-        //     new_attributes.insert(key.clone(), value.clone());
-        //   }
-        // }
-
-        output.push(Lexeme::OpenTag {
-          name: name.clone(),
+        let mut new_children = vec![];
+        size += 1
+          + expand_template(
+            usage,
+            children,
+            false,
+            &mut new_children,
+            warnings,
+          );
+        output.push(Node::Element(Element {
+          name: Cow::Borrowed(name),
           attributes: new_attributes,
           is_empty: *is_empty,
-        });
+          children: new_children,
+          span: span.clone(),
+        }));
       },
-      Lexeme::Directive { name, .. } => {
-        if name == "children" {
-          output.append(&mut base.child_span.to_vec());
-        } else {
-          output.push(element.clone());
-        }
+      Node::Leaf(lm, span) => {
+        size += 1;
+        output.push(Node::Leaf(lm.as_borrowed(), span.clone()));
       },
-      _ => output.push(element.clone()),
     }
   }
+  size
 }
 
-fn compilation_pass(
-  mut source: &[Lexeme],
-  templates: &Templates,
-) -> Result<(Vec<Lexeme>, usize)> {
-  let mut output: Vec<Lexeme> = vec![];
-  let mut num_expanded = 0;
-  while let Some(lm) = source.first() {
-    match lm {
-      Lexeme::OpenTag { name, .. } => {
-        if let Some(tmp) = templates.get(name) {
-          num_expanded += 1;
-          let (base, new_tail) =
-            parse_element(source).ctx(format!("at template usage {}", name))?;
-          expand_template(&base, tmp, &mut output);
-          source = new_tail;
-          continue;
-        } else {
-          output.push(lm.clone());
+/// Expands every template usage in a document
+struct Expander<'a, 't, 's> {
+  templates: &'a Templates<'t>,
+  scopes: &'s Scopes,
+  /// Names of the templates currently being expanded and where
+  /// they were used, innermost last, used to detect cycles
+  stack: Vec<(&'a str, Span)>,
+  /// Number of nodes created by expanding templates so far
+  size: usize,
+  warnings: Vec<Warning>,
+  used: HashSet<&'a str>,
+}
+
+/// Whether `nodes` hold anything other than whitespace
+fn has_content(nodes: &[Node<'_>]) -> bool {
+  nodes.iter().any(|node| match node {
+    Node::Leaf(HtmlElement::Text(text), _) => !text.trim().is_empty(),
+    Node::Leaf(HtmlElement::Comment(_), _) => false,
+    _ => true,
+  })
+}
+
+/// Whether a template places the children of its usages
+fn has_children_slot(template: &[Node<'_>]) -> bool {
+  descendants(template).any(|node| {
+    matches!(
+      node,
+      Node::Leaf(HtmlElement::Directive { name, .. }, _) if name == "children"
+    )
+  })
+}
+
+impl<'a> Expander<'a, '_, '_> {
+  /// Notes for every template usage being expanded, innermost
+  /// first
+  fn frames(&self) -> impl Iterator<Item = Note> + '_ {
+    self
+      .stack
+      .iter()
+      .rev()
+      .map(|(name, span)| Note::at(format!("in template usage {}", name), span))
+  }
+
+  fn warn(&mut self, warning: Warning) {
+    let warning = self.frames().fold(warning, Warning::note);
+    self.warnings.push(warning);
+  }
+
+  /// Warns about attributes of a usage that its template does
+  /// not take, or that do not match their declared types
+  fn check_parameters(&mut self, usage: &Element<'_>, template: &Element<'_>) {
+    let referenced = referenced_parameters(template);
+    // Declarations were checked when the template was defined
+    let declared = declared_parameters(template).unwrap_or_default();
+    let spreads = has_spread(template);
+    let mut keys: Vec<_> = usage.attributes.keys().collect();
+    keys.sort();
+    for key in keys {
+      let value = &usage.attributes[key];
+      if let Some((_, param_type)) =
+        declared.iter().find(|(name, _)| *name == key.as_ref())
+      {
+        let value = literal_value(value);
+        if !param_type.accepts(value) {
+          let message = format!(
+            "`{}` is not a valid {} for parameter `{}` of `{}`",
+            value, param_type, key, template.name
+          );
+          self.warn(
+            Warning::new(Lint::InvalidParameter, message).at(&usage.span),
+          );
         }
-      },
-      _ => output.push(lm.clone()),
+        continue;
+      }
+      // Without a spread, class and style still merge onto the
+      // template's roots
+      if spreads
+        || referenced.contains(key.as_ref())
+        || MERGED_ATTRIBUTES.contains(&key.as_ref())
+      {
+        continue;
+      }
+      let message = format!(
+        "`{}` does not take a `{}` parameter, so it is dropped",
+        template.name, key
+      );
+      let mut warning =
+        Warning::new(Lint::UnknownParameter, message).at(&usage.span);
+      let names = referenced
+        .iter()
+        .copied()
+        .chain(declared.iter().map(|(name, _)| *name));
+      if let Some(suggestion) = suggest(key, names) {
+        let help = format!("did you mean `{}`?", suggestion);
+        warning = warning.note(Note::new(help));
+      }
+      self.warn(warning);
     }
-    source = &source[1..];
   }
-  Ok((output, num_expanded))
-}
 
-pub fn compile_source(
-  source: impl AsRef<str>,
-  templates: &Templates,
-) -> Result<Vec<Lexeme>> {
-  let mut source: Vec<Lexeme> = parse_html(source.as_ref())?;
-  for i in 1..=RECURSION_LIMIT {
-    let (new_source, num_expanded) = compilation_pass(&source, templates)?;
-    source = new_source;
-    if num_expanded == 0 {
-      break;
+  /// Removes the `<@fragment>` children of a template usage,
+  /// expanding their contents
+  fn take_fragments(
+    &mut self,
+    element: &mut Element<'a>,
+  ) -> Result<Fragments<'a>> {
+    let mut fragments = Fragments::new();
+    let mut children = vec![];
+    for child in std::mem::take(&mut element.children) {
+      let fragment = match child {
+        Node::Element(fragment) if fragment.name == "@fragment" => fragment,
+        child => {
+          children.push(child);
+          continue;
+        },
+      };
+      let Some(name) = fragment.get_attribute("name") else {
+        return Err(
+          compile_error("`<@fragment>` needs a `name` attribute")
+            .at(&fragment.span),
+        );
+      };
+      if fragments.contains_key(name) {
+        return Err(
+          compile_error(format!(
+            "fragment `{}` is passed more than once",
+            name
+          ))
+          .at(&fragment.span),
+        );
+      }
+      let name = name.to_string();
+      let mut nodes = vec![];
+      self.expand(fragment.children, &mut nodes)?;
+      fragments.insert(name, nodes);
     }
-    if source.len() >= LEXEME_MEMORY_LIMIT {
-      return Err(compile_error("reached memory limit expanding templates"));
+    element.children = children;
+    Ok(fragments)
+  }
+
+  /// Replaces the `<@children />` and `@name` placeholders left
+  /// in an expanded template body with the children and fragments
+  /// of its usage, which are already expanded. `@name` is only a
+  /// placeholder when the usage passes that fragment or `name` is
+  /// one of the `declared` parameters. Spliced nodes are not
+  /// searched, as any placeholders in them belong to an enclosing
+  /// template
+  fn splice(
+    &mut self,
+    nodes: Vec<Node<'a>>,
+    usage: &Element<'a>,
+    fragments: &Fragments<'a>,
+    declared: &HashSet<&str>,
+    output: &mut Vec<Node<'a>>,
+  ) {
+    for node in nodes {
+      let (lm, span) = match node {
+        Node::Element(mut element) => {
+          let children = std::mem::take(&mut element.children);
+          let spliced = &mut element.children;
+          self.splice(children, usage, fragments, declared, spliced);
+          output.push(Node::Element(element));
+          continue;
+        },
+        Node::Leaf(Lexeme::Directive { name, .. }, _) if name == "children" => {
+          self.size += usage.children.iter().map(Node::size).sum::<usize>();
+          output.extend(usage.children.iter().cloned());
+          continue;
+        },
+        Node::Leaf(lm, span) => (lm, span),
+      };
+      let reference = match &lm {
+        Lexeme::Text(text) => fragment_reference(text),
+        _ => None,
+      };
+      let Some(name) = reference else {
+        let escaped = match &lm {
+          Lexeme::Text(text) => escaped_fragment_reference(text),
+          _ => None,
+        };
+        let lm = escaped.map_or(lm, |text| Lexeme::Text(text.into()));
+        output.push(Node::Leaf(lm, span));
+        continue;
+      };
+      if let Some(fragment) = fragments.get(name) {
+        self.size += fragment.iter().map(Node::size).sum::<usize>();
+        output.extend(fragment.iter().cloned());
+      } else if !declared.contains(name) {
+        output.push(Node::Leaf(lm, span));
+      } else {
+        let message = format!(
+          "`{}` places `@{}`, which this usage does not pass as a fragment",
+          usage.name, name
+        );
+        self.warn(Warning::new(Lint::MissingParameter, message).at(&span));
+      }
     }
-    if i == RECURSION_LIMIT {
-      return Err(compile_error("reached recursion limit expanding templates"));
+  }
+
+  fn check_size(&self, span: &Span) -> Result<()> {
+    if self.size >= LEXEME_MEMORY_LIMIT {
+      return Err(
+        compile_error("reached memory limit expanding templates").at(span),
+      );
     }
+    Ok(())
   }
-  Ok(source.to_vec())
+
+  fn expand(
+    &mut self,
+    nodes: Vec<Node<'a>>,
+    output: &mut Vec<Node<'a>>,
+  ) -> Result<()> {
+    for node in nodes {
+      let mut element = match node {
+        Node::Element(element) => element,
+        leaf => {
+          if let Node::Leaf(
+            HtmlElement::Directive {
+              name, attributes, ..
+            },
+            span,
+          ) = &leaf
+          {
+            // Imports were resolved before expansion
+            if name == "import" {
+              continue;
+            }
+            // Inside of a template, this is where the children of
+            // its usage are spliced
+            if name == "children" && !self.stack.is_empty() {
+              output.push(leaf);
+              continue;
+            }
+            if let Some(problem) = directive_problem(name, attributes) {
+              self.warn(
+                Warning::new(Lint::UnresolvedDirective, problem).at(span),
+              );
+            }
+          }
+          output.push(leaf);
+          continue;
+        },
+      };
+      let scope = element.span.file.as_ref().and_then(|f| self.scopes.get(f));
+      let resolved = resolve(self.templates, scope, &element.name);
+      let fragments = match resolved {
+        Some(_) => self.take_fragments(&mut element)?,
+        None => Fragments::new(),
+      };
+
+      // Children belong to the caller, so they are expanded
+      // before entering any template
+      let mut children = vec![];
+      self.expand(std::mem::take(&mut element.children), &mut children)?;
+      element.children = children;
+
+      // Directives with HTML bodies are parsed as elements, and
+      // are only expanded when serialized
+      if let Some(name) = element.name.strip_prefix('@') {
+        if let Some(problem) = directive_problem(name, &element.attributes) {
+          self.warn(
+            Warning::new(Lint::UnresolvedDirective, problem).at(&element.span),
+          );
+        }
+        output.push(Node::Element(element));
+        continue;
+      }
+
+      let Some((name, tmp)) = resolved else {
+        if looks_like_component(&element.name) {
+          let mut warning = Warning::new(
+            Lint::UnknownComponent,
+            format!(
+              "`{}` matches no template, so it is output as an HTML tag",
+              element.name
+            ),
+          )
+          .at(&element.span);
+          let names = visible_names(self.templates, scope);
+          if let Some(suggestion) =
+            suggest(&element.name, names.iter().map(String::as_str))
+          {
+            let help = format!("did you mean `{}`?", suggestion);
+            warning = warning.note(Note::new(help));
+          }
+          self.warn(warning);
+        }
+        output.push(Node::Element(element));
+        continue;
+      };
+      self.used.insert(name);
+      self.check_parameters(&element, tmp);
+      if self.stack.iter().any(|(frame, _)| *frame == name) {
+        let cycle = self
+          .stack
+          .iter()
+          .map(|(frame, _)| *frame)
+          .skip_while(|frame| *frame != name)
+          .chain(std::iter::once(name.as_str()))
+          .collect::<Vec<_>>()
+          .join(" -> ");
+        return Err(
+          compile_error(format!("template expansion cycle: {}", cycle))
+            .at(&element.span),
+        );
+      }
+      if self.stack.len() >= RECURSION_LIMIT {
+        return Err(
+          compile_error("reached recursion limit expanding templates")
+            .at(&element.span),
+        );
+      }
+
+      if has_content(&element.children) && !has_children_slot(&tmp.children) {
+        let message = format!(
+          "`{}` has no `<@children />`, so the children of this usage are \
+           dropped",
+          name
+        );
+        self
+          .warn(Warning::new(Lint::IgnoredChildren, message).at(&element.span));
+      }
+
+      let placed = referenced_fragments(tmp);
+      let mut dropped: Vec<&String> = fragments
+        .keys()
+        .filter(|fragment| !placed.contains(fragment.as_str()))
+        .collect();
+      dropped.sort();
+      for fragment in dropped {
+        let message = format!(
+          "`{}` does not place `@{}`, so this fragment is dropped",
+          name, fragment
+        );
+        self
+          .warn(Warning::new(Lint::IgnoredChildren, message).at(&element.span));
+      }
+
+      self.stack.push((name, element.span.clone()));
+      let mut body = vec![];
+      let mut warnings = vec![];
+      let usage = Usage {
+        element: &element,
+        forwarded: forwarded_attributes(&element, tmp),
+        spreads: has_spread(tmp),
+      };
+      self.size +=
+        expand_template(&usage, &tmp.children, true, &mut body, &mut warnings);
+      for warning in warnings {
+        self.warn(warning);
+      }
+      self.check_size(&element.span)?;
+      let mut expanded = vec![];
+      self.expand(body, &mut expanded).map_err(|e| {
+        e.note_at(format!("in template usage {}", name), &element.span)
+      })?;
+      // The children and fragments were expanded before entering
+      // the template, so they are spliced in after its body
+      let declared = declared_parameters(tmp)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+      self.splice(expanded, &element, &fragments, &declared, output);
+      self.check_size(&element.span)?;
+      self.stack.pop();
+    }
+    Ok(())
+  }
+}
+
+pub fn compile_source<'a>(
+  source: &'a str,
+  templates: &'a Templates<'_>,
+  mode: ParseMode,
+) -> Result<(Vec<Node<'a>>, Vec<Warning>)> {
+  compile_source_from(source, None, templates, mode)
+}
+
+/// Compiles a page, naming `file` as its source
+pub fn compile_source_from<'a>(
+  source: &'a str,
+  file: Option<Rc<Path>>,
+  templates: &'a Templates<'_>,
+  mode: ParseMode,
+) -> Result<(Vec<Node<'a>>, Vec<Warning>)> {
+  let source = parse_document_from(source, mode, file)?;
+  let expansion = expand_document(source, templates, &Scopes::new())?;
+  Ok((expansion.nodes, expansion.warnings))
+}
+
+/// A document with its templates expanded
+pub struct Expansion<'a> {
+  pub nodes: Vec<Node<'a>>,
+  pub warnings: Vec<Warning>,
+  /// Names of every template that was expanded
+  pub used: HashSet<&'a str>,
+}
+
+/// Expands every template usage in `nodes`, resolving names in
+/// each node by the scope of the file it came from
+pub fn expand_document<'a>(
+  nodes: Vec<Node<'a>>,
+  templates: &'a Templates<'_>,
+  scopes: &Scopes,
+) -> Result<Expansion<'a>> {
+  let mut output = Vec::with_capacity(nodes.len());
+  let mut expander = Expander {
+    templates,
+    scopes,
+    stack: vec![],
+    size: 0,
+    warnings: vec![],
+    used: HashSet::new(),
+  };
+  expander.expand(nodes, &mut output)?;
+  Ok(Expansion {
+    nodes: output,
+    warnings: expander.warnings,
+    used: expander.used,
+  })
 }
 
 pub fn compile_source_file(
   path: impl AsRef<Path>,
-  templates: &Templates,
-) -> Result<Vec<Lexeme>> {
+  templates: &Templates<'_>,
+  mode: ParseMode,
+) -> Result<(Vec<Node<'static>>, Vec<Warning>)> {
   let file = read_file(&path)?;
-  compile_source(file, templates)
-    .ctx(format!("while compiling file {}", path.as_ref().display()))
+  let (source, warnings) =
+    compile_source_from(&file, Some(path.as_ref().into()), templates, mode)
+      .ctx(format!("while compiling file {}", path.as_ref().display()))?;
+  let source = source.into_iter().map(Node::into_owned).collect();
+  Ok((source, warnings))
 }
 
-pub fn serialize(output: &Vec<Lexeme>) -> String {
-  output
-    .iter()
-    .map(|lm| lm.serialize())
-    .collect::<Vec<_>>()
-    .join("")
+pub fn serialize(output: &[Node<'_>]) -> String {
+  crate::tree::serialize(output)
 }
 
-pub fn serialize_mini(output: &Vec<Lexeme>) -> String {
-  output
-    .iter()
-    .map(|lm| lm.serialize())
-    .collect::<Vec<_>>()
-    .join("")
+pub fn serialize_mini(output: &[Node<'_>]) -> String {
+  crate::tree::serialize(output)
 }
 
 pub struct Compiler {
-  templates: HashMap<String, Element>,
+  templates: Templates<'static>,
+  mode: ParseMode,
+  transforms: Vec<Transform>,
+  lints: LintConfig,
+  warnings: Vec<Warning>,
+  errors: Vec<crate::trace::Error>,
+  /// The scope of every template file and page loaded so far
+  scopes: Scopes,
+  /// The namespace of every file in the template folders, found
+  /// before any of them is loaded so that files importing each
+  /// other get the same namespace whichever is read first
+  folder_namespaces: HashMap<Rc<Path>, Option<String>>,
+  /// Names of the templates used by pages compiled so far
+  used: HashSet<String>,
+  /// Whether assets are named by their contents
+  fingerprint: bool,
+  /// The hashed names of the assets of the source folder being
+  /// compiled, when fingerprinting
+  assets: Option<Assets>,
+}
+
+impl Default for Compiler {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Compiler {
   pub fn new() -> Self {
     Self {
       templates: Default::default(),
+      mode: Default::default(),
+      transforms: vec![],
+      lints: Default::default(),
+      warnings: vec![],
+      errors: vec![],
+      scopes: Default::default(),
+      folder_namespaces: Default::default(),
+      used: Default::default(),
+      fingerprint: false,
+      assets: None,
+    }
+  }
+
+  /// Sets the level of the lint named `code`, or of every lint
+  /// that warns by default when `code` is `warnings`
+  pub fn with_lint_level(
+    &mut self,
+    code: &str,
+    level: Level,
+  ) -> Result<&mut Self> {
+    self.lints.set(code, level)?;
+    Ok(self)
+  }
+
+  /// Every warning reported so far that was not allowed,
+  /// including denied ones
+  pub fn warnings(&self) -> &[Warning] {
+    &self.warnings
+  }
+
+  /// Keeps `warnings` at their configured levels, failing if
+  /// any of them are denied
+  fn report(&mut self, warnings: Vec<Warning>) -> Result<()> {
+    let warnings = self.lints.apply(warnings);
+    let denied = warnings
+      .iter()
+      .filter(|warning| warning.level == Level::Deny)
+      .count();
+    self.warnings.extend(warnings);
+    match denied {
+      0 => Ok(()),
+      1 => Err(compile_error("1 warning was denied")),
+      _ => Err(compile_error(format!("{} warnings were denied", denied))),
     }
   }
 
+  /// Warns about every template that no page compiled so far has
+  /// used, directly or through another template. Call this once
+  /// every page is compiled
+  pub fn check_unused_templates(&mut self) -> Result<&mut Self> {
+    let mut unused: Vec<_> = self
+      .templates
+      .iter()
+      .filter(|(name, _)| !self.used.contains(*name))
+      .collect();
+    unused.sort_by_key(|(name, _)| *name);
+    let warnings = unused
+      .into_iter()
+      .map(|(name, template)| {
+        let message = format!("template `{}` is never used", name);
+        Warning::new(Lint::UnusedTemplate, message).at(&template.span)
+      })
+      .collect();
+    self.report(warnings)?;
+    Ok(self)
+  }
+
+  /// Sets how templates and sources added after this call are
+  /// parsed. Use `ParseMode::Tolerant` to accept HTML that
+  /// leaves out optional end tags
+  pub fn with_parse_mode(&mut self, mode: ParseMode) -> &mut Self {
+    self.mode = mode;
+    self
+  }
+
+  /// Adds a transform applied to every page after its templates
+  /// are expanded
+  pub fn with_transform(&mut self, transform: Transform) -> &mut Self {
+    self.transforms.push(transform);
+    self
+  }
+
+  /// Names the CSS, JavaScript, images and fonts copied by
+  /// `with_src_folder` by a hash of their contents, rewriting the
+  /// references of pages and stylesheets to match. A
+  /// `manifest.json` mapping the original names to the hashed
+  /// ones is written alongside them
+  pub fn with_fingerprinting(&mut self) -> &mut Self {
+    self.fingerprint = true;
+    self
+  }
+
+  pub fn with_transforms_file(
+    &mut self,
+    path: impl AsRef<Path>,
+  ) -> Result<&mut Self> {
+    let transforms = parse_transforms_file(path)?;
+    self.transforms.extend(transforms);
+    Ok(self)
+  }
+
   pub fn with_template_file(
     &mut self,
     path: impl AsRef<Path>,
   ) -> Result<&mut Self> {
-    let templates = parse_templates_file(path)?;
-    self.templates.extend(templates);
+    self.load_template_file(path.as_ref().into(), None)?;
     Ok(self)
   }
 
+  /// Loads the templates in `path` under `namespace`, along with
+  /// the files it imports. Files that are already loaded are
+  /// skipped
+  fn load_template_file(
+    &mut self,
+    path: Rc<Path>,
+    namespace: Option<String>,
+  ) -> Result<()> {
+    if self.scopes.contains_key(&path) {
+      return Ok(());
+    }
+    let parsed = parse_templates_file(&path, self.mode)?;
+    // Registered before loading imports so that files importing
+    // each other are only loaded once
+    let scope = Scope {
+      namespace: namespace.clone(),
+      aliases: Default::default(),
+    };
+    self.scopes.insert(path.clone(), scope);
+    let aliases = self
+      .load_imports(&parsed.imports)
+      .ctx(format!("in file {}", path.display()))?;
+    if let Some(scope) = self.scopes.get_mut(&path) {
+      scope.aliases = aliases;
+    }
+    self
+      .report(parsed.warnings)
+      .ctx(format!("in file {}", path.display()))?;
+    for (name, template) in parsed.templates {
+      let name = qualify(namespace.as_deref(), &name);
+      define_template(&mut self.templates, name, template)?;
+    }
+    Ok(())
+  }
+
+  /// Loads every imported file, returning the namespaces they
+  /// were loaded under by alias. A file in a template folder
+  /// keeps the namespace of its folder
+  fn load_imports(
+    &mut self,
+    imports: &[Import],
+  ) -> Result<HashMap<String, Option<String>>> {
+    let mut aliases = HashMap::new();
+    for import in imports {
+      let path = import.path();
+      let namespace = match self.folder_namespaces.get(&path) {
+        Some(namespace) => namespace.clone(),
+        None => Some(self.import_namespace(&path)),
+      };
+      self
+        .load_template_file(path.clone(), namespace)
+        .map_err(|e| e.note_at("imported here", &import.span))?;
+      let namespace = self.scopes[&path].namespace.clone();
+      aliases.insert(import.alias.clone(), namespace);
+    }
+    Ok(aliases)
+  }
+
+  /// A namespace for an imported file outside of the template
+  /// folders, named after the file and numbered if another file
+  /// already has that name
+  fn import_namespace(&self, path: &Path) -> String {
+    let stem = path
+      .file_stem()
+      .unwrap_or(path.as_os_str())
+      .to_string_lossy();
+    let taken = |namespace: &str| {
+      self
+        .scopes
+        .values()
+        .map(|scope| &scope.namespace)
+        .chain(self.folder_namespaces.values())
+        .any(|taken| taken.as_deref() == Some(namespace))
+    };
+    let mut namespace = stem.to_string();
+    let mut count = 1;
+    while taken(&namespace) {
+      count += 1;
+      namespace = format!("{}-{}", stem, count);
+    }
+    namespace
+  }
+
   pub fn with_src(
     &mut self,
     from: impl AsRef<Path>,
     to: impl AsRef<Path>,
   ) -> Result<&mut Self> {
-    let source = compile_source_file(from, &self.templates)?;
+    let file = read_file(&from)?;
+    let context =
+      || format!("while compiling file {}", from.as_ref().display());
+    let path: Rc<Path> = from.as_ref().into();
+    let nodes = parse_document_from(&file, self.mode, Some(path.clone()))
+      .ctx(context())?;
+    let imports = find_imports(&nodes).ctx(context())?;
+    let aliases = self.load_imports(&imports).ctx(context())?;
+    let scope = Scope {
+      namespace: None,
+      aliases,
+    };
+    self.scopes.insert(path, scope);
+    let Expansion {
+      nodes: mut source,
+      warnings,
+      used,
+    } = expand_document(nodes, &self.templates, &self.scopes).ctx(context())?;
+    for transform in &self.transforms {
+      transform.apply(&mut source);
+    }
+    if let Some(assets) = &self.assets {
+      assets.rewrite_page(&mut source, from.as_ref());
+    }
     let serial = serialize(&source);
+    let used: Vec<String> = used.into_iter().map(String::from).collect();
+    self.used.extend(used);
+    self.report(warnings).ctx(context())?;
     let mut new_file = std::fs::File::create(to)?;
     new_file.write_all(serial.as_bytes())?;
     Ok(self)
   }
 
+  /// Loads every template file in `from`. Templates in a
+  /// subfolder are namespaced by its name, so `icons/rust.html`
+  /// defining `Rust` is used as `<icons:Rust />`
   pub fn with_template_folder(
     &mut self,
     from: impl AsRef<Path>,
   ) -> Result<&mut Self> {
-    for file in std::fs::read_dir(&from)? {
-      let file =
-        file.ctx(format!("reading directory {}", from.as_ref().display()))?;
-      let path = file.path();
-      let last = path.components().last().ctx("empty path encountered")?;
-      let ft = match file.file_type() {
-        Ok(ft) => ft,
-        Err(_) => continue,
-      };
-      if ft.is_dir() {
-        self.with_template_folder(&from.as_ref().join(&last))?;
-        continue;
-      }
-      let ext = match path.extension() {
-        Some(ext) => ext,
-        None => continue,
-      };
-
-      if ext != "html" {
-        continue;
-      }
-      self.with_template_file(&path)?;
+    let mut files = vec![];
+    list_template_folder(from.as_ref(), None, &mut files)?;
+    files.sort();
+    for (path, namespace) in &files {
+      self
+        .folder_namespaces
+        .insert(path.clone(), namespace.clone());
+    }
+    for (path, namespace) in files {
+      self.load_template_file(path, namespace)?;
     }
     Ok(self)
   }
 
+  /// Errors from every file that failed to compile, in the
+  /// order they were found
+  pub fn errors(&self) -> &[crate::trace::Error] {
+    &self.errors
+  }
+
+  /// Compiles every page in `from` into `to`, copying other files
+  /// as they are. A file that fails does not stop the rest from
+  /// being compiled; its error is kept in `errors` and the call
+  /// fails once the whole folder is done
   pub fn with_src_folder(
     &mut self,
     from: impl AsRef<Path>,
     to: impl AsRef<Path>,
   ) -> Result<&mut Self> {
-    let _ = std::fs::remove_dir_all(&to);
-    let _ = std::fs::create_dir_all(&to);
-    for file in std::fs::read_dir(&from)? {
-      let file =
-        file.ctx(format!("reading directory {}", from.as_ref().display()))?;
-      let path = file.path();
-      let last = path.components().last().ctx("empty path encountered")?;
-      let destination = to.as_ref().join(&last);
-      let ft = match file.file_type() {
-        Ok(ft) => ft,
-        Err(_) => continue,
-      };
-      if ft.is_dir() {
-        self.with_src_folder(
-          &from.as_ref().join(&last),
-          &to.as_ref().join(&last),
-        )?;
-        continue;
+    let before = self.errors.len();
+    if self.fingerprint {
+      self.assets = Some(Assets::scan(&from)?);
+    }
+    self.compile_folder(from.as_ref(), to.as_ref());
+    if let Some(assets) = &self.assets {
+      let manifest = to.as_ref().join("manifest.json");
+      std::fs::write(&manifest, assets.manifest())
+        .ctx(format!("writing to file: {}", manifest.display()))?;
+    }
+    match self.errors.len() - before {
+      0 => Ok(self),
+      1 => Err(compile_error("could not compile 1 file")),
+      failed => {
+        Err(compile_error(format!("could not compile {} files", failed)))
+      },
+    }
+  }
+
+  fn compile_folder(&mut self, from: &Path, to: &Path) {
+    let _ = std::fs::remove_dir_all(to);
+    let _ = std::fs::create_dir_all(to);
+    let files = match std::fs::read_dir(from)
+      .ctx(format!("reading directory {}", from.display()))
+    {
+      Ok(files) => files,
+      Err(e) => {
+        self.errors.push(e);
+        return;
+      },
+    };
+    for file in files {
+      if let Err(e) = self.compile_entry(file, from, to) {
+        self.errors.push(e);
       }
-      let ext = match path.extension() {
-        Some(ext) => ext,
-        None => continue,
+    }
+  }
+
+  fn compile_entry(
+    &mut self,
+    file: std::io::Result<std::fs::DirEntry>,
+    from: &Path,
+    to: &Path,
+  ) -> Result<()> {
+    let file = file.ctx(format!("reading directory {}", from.display()))?;
+    let path = file.path();
+    let last = path
+      .components()
+      .next_back()
+      .ctx("empty path encountered")?;
+    let destination = to.join(last);
+    let ft = match file.file_type() {
+      Ok(ft) => ft,
+      Err(_) => return Ok(()),
+    };
+    if ft.is_dir() {
+      self.compile_folder(&from.join(last), &destination);
+      return Ok(());
+    }
+    let ext = match path.extension() {
+      Some(ext) => ext,
+      None => return Ok(()),
+    };
+
+    // Copy file but do not compile, naming assets by their
+    // contents when fingerprinting
+    if ext != "html" {
+      let asset = self
+        .assets
+        .as_ref()
+        .and_then(|assets| Some((assets, assets.hashed_name(&path)?)));
+      let (destination, file) = match asset {
+        Some((assets, name)) => (to.join(name), assets.read(&path)?),
+        None => (
+          destination,
+          std::fs::read(&path)
+            .ctx(format!("opening file to read: {}", path.display()))?,
+        ),
       };
+      let mut new_file = std::fs::File::create(&destination)
+        .ctx(format!("opening file to write: {}", destination.display()))?;
+      new_file
+        .write_all(&file)
+        .ctx(format!("writing to file: {}", destination.display()))?;
+      return Ok(());
+    }
+    self.with_src(&path, destination)?;
+    Ok(())
+  }
+}
 
-      // Copy file but do not compile
-      if ext != "html" {
-        let file = std::fs::read(&path)
-          .ctx(format!("opening file to read: {}", path.display()))?;
-        let mut new_file = std::fs::File::create(&destination)
-          .ctx(format!("opening file to write: {}", destination.display()))?;
-        new_file
-          .write_all(&file)
-          .ctx(format!("writing to file: {}", destination.display()))?;
-        continue;
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TEMPLATES: &str = "<Card><div><@children /></div></Card>\
+    <Outer><Card><@children /></Card></Outer>";
+
+  fn compile(source: &str) -> (String, Vec<Lint>) {
+    compile_with(TEMPLATES, source)
+  }
+
+  fn compile_with(templates: &str, source: &str) -> (String, Vec<Lint>) {
+    let templates = parse_templates(templates, ParseMode::Strict).unwrap();
+    let (nodes, warnings) =
+      compile_source(source, &templates.templates, ParseMode::Strict).unwrap();
+    let lints = warnings.iter().map(|warning| warning.lint).collect();
+    (serialize(&nodes), lints)
+  }
+
+  #[test]
+  fn expands_children_once() {
+    let (output, lints) = compile("<Card><Cardd>x</Cardd></Card>");
+    assert_eq!(output, "<div><Cardd>x</Cardd></div>");
+    assert_eq!(lints, [Lint::UnknownComponent]);
+    let (output, lints) = compile("<Outer><Cardd>x</Cardd><@bogus /></Outer>");
+    assert_eq!(output, "<div><Cardd>x</Cardd><!-- --></div>");
+    assert_eq!(lints, [Lint::UnknownComponent, Lint::UnresolvedDirective]);
+  }
+
+  /// An empty folder for a test to write files to
+  fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "html-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn imported_template_files_keep_their_folder_namespace() {
+    for (importer, imported) in [("z", "a"), ("a", "z")] {
+      let dir = temp_dir(&format!("imports-{}", importer));
+      let write = |name: &str, contents: &str| {
+        std::fs::write(dir.join(format!("{}.html", name)), contents).unwrap()
+      };
+      write(imported, "<Icon><i></i></Icon>");
+      write(
+        importer,
+        &format!(
+          "<@import from=\"{}.html\" as=\"ic\" /><Wrap><ic:Icon /></Wrap>",
+          imported
+        ),
+      );
+      let mut compiler = Compiler::new();
+      compiler.with_template_folder(&dir).unwrap();
+      let mut names: Vec<_> = compiler.templates.keys().cloned().collect();
+      names.sort();
+      assert_eq!(names, ["Icon", "Wrap"]);
+      std::fs::remove_dir_all(&dir).unwrap();
+    }
+  }
+
+  #[test]
+  fn keeps_escaped_at_signs_literal() {
+    let templates = parse_templates(
+      r#"<Tag t=""><img title="&#64;x" alt="@@y" src=@t @...></Tag>"#,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let (nodes, warnings) = compile_source(
+      r#"<Tag t="@@a" data-x="@@b" /><img title="@@c">"#,
+      &templates.templates,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    let output = serialize(&nodes);
+    for attribute in [
+      r#"title="@x""#,
+      r#"alt="@y""#,
+      r#"src="@a""#,
+      r#"data-x="@b""#,
+    ] {
+      assert!(output.contains(attribute), "{}", output);
+    }
+    assert!(output.ends_with(r#"<img title="@c"/>"#), "{}", output);
+  }
+
+  #[test]
+  fn borrows_expanded_nodes_from_templates_and_usages() {
+    let templates = parse_templates(
+      r#"<Link href="url"><a class="link" href=@href>go</a></Link>"#,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let (nodes, _) = compile_source(
+      r#"<Link href="/home" />"#,
+      &templates.templates,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let Node::Element(link) = &nodes[0] else {
+      panic!("expected an element, found {:?}", nodes[0]);
+    };
+    assert!(matches!(link.name, Cow::Borrowed("a")));
+    assert!(matches!(link.attributes["class"], Cow::Borrowed("link")));
+    assert!(matches!(link.attributes["href"], Cow::Borrowed("/home")));
+    assert!(matches!(
+      &link.children[0],
+      Node::Leaf(HtmlElement::Text(Cow::Borrowed("go")), _)
+    ));
+  }
+
+  #[test]
+  fn keeps_template_spans_on_expanded_nodes() {
+    let template_file: Rc<Path> = Path::new("templates/card.html").into();
+    let templates = parse_templates_from(
+      "<Card>\n  <div>\n    <@children />\n  </div>\n</Card>",
+      ParseMode::Strict,
+      Some(template_file.clone()),
+    )
+    .unwrap();
+    let page_file: Rc<Path> = Path::new("index.html").into();
+    let (nodes, _) = compile_source_from(
+      "<main><Card><b>x</b></Card></main>",
+      Some(page_file.clone()),
+      &templates.templates,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let div = find(&nodes, |element| element.name == "div").unwrap();
+    assert_eq!(div.span.file.as_ref(), Some(&template_file));
+    assert_eq!((div.span.line, div.span.column), (2, 3));
+    let b = find(&nodes, |element| element.name == "b").unwrap();
+    assert_eq!(b.span.file.as_ref(), Some(&page_file));
+    assert_eq!((b.span.line, b.span.column), (1, 13));
+  }
+
+  #[test]
+  fn keeps_compiling_after_a_failing_page() {
+    let dir = temp_dir("failing-pages");
+    let (src, out) = (dir.join("src"), dir.join("out"));
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("bad.html"), "<div>").unwrap();
+    std::fs::write(src.join("good.html"), "<p>ok</p>").unwrap();
+    std::fs::write(src.join("style.css"), "p {}").unwrap();
+    std::fs::write(src.join("sub/worse.html"), "<p></div>").unwrap();
+    std::fs::write(src.join("sub/fine.html"), "<b>ok</b>").unwrap();
+    let mut compiler = Compiler::new();
+    let error = compiler.with_src_folder(&src, &out).err().unwrap();
+    assert_eq!(error.reason, "could not compile 2 files");
+    let mut failed: Vec<_> = compiler
+      .errors()
+      .iter()
+      .map(|error| {
+        let span = error.span.as_ref().unwrap();
+        span.file.as_ref().unwrap().to_path_buf()
+      })
+      .collect();
+    failed.sort();
+    assert_eq!(failed, [src.join("bad.html"), src.join("sub/worse.html")]);
+    let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+    assert_eq!(read("good.html"), "<p>ok</p>");
+    assert_eq!(read("sub/fine.html"), "<b>ok</b>");
+    assert_eq!(read("style.css"), "p {}");
+    assert!(!out.join("bad.html").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn transforms_and_fingerprints_inside_directive_bodies() {
+    let dir = temp_dir("directive-bodies");
+    let (src, out) = (dir.join("src"), dir.join("out"));
+    std::fs::create_dir_all(src.join("img")).unwrap();
+    std::fs::write(src.join("img/a.png"), "png").unwrap();
+    std::fs::write(
+      src.join("index.html"),
+      r#"<@details summary=s><img src="img/a.png"></@details>"#,
+    )
+    .unwrap();
+    let mut compiler = Compiler::new();
+    let transforms = r#"<transform select="img" set:loading="lazy" />"#;
+    for transform in crate::transform::parse_transforms(transforms).unwrap() {
+      compiler.with_transform(transform);
+    }
+    compiler
+      .with_fingerprinting()
+      .with_src_folder(&src, &out)
+      .unwrap();
+    let hashed = crate::assets::fingerprinted_name(Path::new("a.png"), b"png");
+    let page = std::fs::read_to_string(out.join("index.html")).unwrap();
+    assert!(
+      page.starts_with("<details><summary>s</summary><img "),
+      "{}",
+      page
+    );
+    let src = format!(r#"src="img/{}""#, hashed.display());
+    assert!(page.contains(&src), "{}", page);
+    assert!(page.contains(r#"loading="lazy""#), "{}", page);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fingerprints_assets_used_by_pages_and_stylesheets() {
+    let dir = temp_dir("fingerprints");
+    let (src, out) = (dir.join("src"), dir.join("out"));
+    std::fs::create_dir_all(src.join("css")).unwrap();
+    std::fs::create_dir_all(src.join("img")).unwrap();
+    let write = |path: &str, contents: &str| {
+      std::fs::write(src.join(path), contents).unwrap()
+    };
+    write("img/a.png", "png");
+    write(
+      "css/base.css",
+      r#"body { background: url("../img/a.png") }"#,
+    );
+    write("css/main.css", r#"@import "base.css";"#);
+    write(
+      "index.html",
+      r#"<link rel="stylesheet" href="css/main.css">"#,
+    );
+    Compiler::new()
+      .with_fingerprinting()
+      .with_src_folder(&src, &out)
+      .unwrap();
+
+    let hash = |path: &str, contents: &str| {
+      let path =
+        crate::assets::fingerprinted_name(Path::new(path), contents.as_bytes());
+      path.to_string_lossy().into_owned()
+    };
+    let image = hash("img/a.png", "png");
+    let base_css = format!(r#"body {{ background: url("../{}") }}"#, image);
+    let base = hash("css/base.css", &base_css);
+    let main_css = format!(r#"@import "{}";"#, &base["css/".len()..]);
+    let main = hash("css/main.css", &main_css);
+    let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+    assert_eq!(
+      read("manifest.json"),
+      format!(
+        "{{\n  \"css/base.css\": \"{}\",\n  \"css/main.css\": \"{}\",\n  \
+         \"img/a.png\": \"{}\"\n}}\n",
+        base, main, image
+      )
+    );
+    assert_eq!(read(&main), main_css);
+    assert_eq!(read(&base), base_css);
+    assert_eq!(read(&image), "png");
+    let page = read("index.html");
+    assert!(page.contains(&format!(r#"href="{}""#, main)), "{}", page);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fails_on_denied_warnings() {
+    let dir = temp_dir("denied-warnings");
+    let file = dir.join("templates.html");
+    std::fs::write(&file, "stray <A><b></b></A> text <B><i></i></B>").unwrap();
+
+    let mut compiler = Compiler::new();
+    compiler
+      .with_lint_level("unexpected-root-node", Level::Deny)
+      .unwrap();
+    let error = compiler.with_template_file(&file).err().unwrap();
+    assert_eq!(error.reason, "2 warnings were denied");
+    let levels: Vec<_> = compiler
+      .warnings()
+      .iter()
+      .map(|warning| (warning.lint, warning.level))
+      .collect();
+    assert_eq!(levels, [(Lint::UnexpectedRootNode, Level::Deny); 2]);
+
+    let mut compiler = Compiler::new();
+    compiler.with_lint_level("warnings", Level::Allow).unwrap();
+    compiler.with_template_file(&file).unwrap();
+    assert!(compiler.warnings().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  /// Defines the only template in each of `sources` in order,
+  /// returning the body of the one that is kept
+  fn define_each(sources: &[&str]) -> Result<String> {
+    let mut templates = Templates::new();
+    for (index, source) in sources.iter().enumerate() {
+      let file: Rc<Path> = Path::new(&format!("{}.html", index)).into();
+      let parsed = parse_templates_from(source, ParseMode::Strict, Some(file))?;
+      for (name, template) in parsed.templates {
+        define_template(&mut templates, name, template)?;
       }
-      self.with_src(&path, destination)?;
     }
-    Ok(self)
+    Ok(serialize(&templates["Card"].children))
+  }
+
+  #[test]
+  fn replaces_templates_marked_override() {
+    let base = "<Card><b></b></Card>";
+    let replacement = "<Card @override><i></i></Card>";
+    assert_eq!(define_each(&[base, replacement]).unwrap(), "<i></i>");
+    assert_eq!(define_each(&[replacement, base]).unwrap(), "<i></i>");
+  }
+
+  #[test]
+  fn rejects_duplicate_templates() {
+    let error = define_each(&["<Card><b></b></Card>", "<Card><i></i></Card>"])
+      .unwrap_err();
+    assert_eq!(error.reason, "template `Card` is defined more than once");
+    assert_eq!(error.span.unwrap().to_string(), "1.html:1:1");
+    let notes: Vec<_> =
+      error.backtrace.iter().map(ToString::to_string).collect();
+    assert_eq!(
+      notes,
+      [
+        "first defined here at 0.html:1:1",
+        "mark one definition `@override` to replace the other"
+      ]
+    );
+    let error = define_each(&[
+      "<Card @override><b></b></Card>",
+      "<Card @override><i></i></Card>",
+    ])
+    .unwrap_err();
+    assert_eq!(
+      error.backtrace[1].message,
+      "only one definition can be marked `@override`"
+    );
+  }
+
+  #[test]
+  fn suggests_similar_template_names() {
+    let templates = parse_templates(TEMPLATES, ParseMode::Strict).unwrap();
+    let (_, warnings) = compile_source(
+      "<Cardd>x</Cardd><Widget />",
+      &templates.templates,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let notes: Vec<_> = warnings
+      .iter()
+      .map(|warning| {
+        assert_eq!(warning.lint, Lint::UnknownComponent);
+        warning
+          .notes
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<_>>()
+      })
+      .collect();
+    assert_eq!(notes, [vec!["did you mean `Card`?"], vec![]]);
+  }
+
+  #[test]
+  fn warns_about_unused_templates() {
+    let dir = temp_dir("unused-templates");
+    let file = dir.join("templates.html");
+    std::fs::write(&file, TEMPLATES).unwrap();
+    std::fs::write(dir.join("page.html"), "<Card>x</Card>").unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+      .with_template_file(&file)
+      .unwrap()
+      .with_src(dir.join("page.html"), dir.join("out.html"))
+      .unwrap()
+      .check_unused_templates()
+      .unwrap();
+    let messages: Vec<_> = compiler
+      .warnings()
+      .iter()
+      .map(|warning| (warning.lint, warning.message.as_str()))
+      .collect();
+    assert_eq!(
+      messages,
+      [(Lint::UnusedTemplate, "template `Outer` is never used")]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  /// The attributes of the first `tag` element in `output`, sorted
+  fn attributes_of(output: &str, tag: &str) -> Vec<(String, String)> {
+    let start = output.find(&format!("<{} ", tag)).unwrap();
+    let end = start + output[start..].find('>').unwrap();
+    let mut attributes: Vec<_> =
+      crate::parse::ordered_attributes(&output[start..=end])
+        .unwrap()
+        .into_iter()
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    attributes.sort();
+    attributes
+  }
+
+  fn pairs(attributes: &[(&str, &str)]) -> Vec<(String, String)> {
+    attributes
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn spreads_onto_the_marked_element() {
+    let (output, lints) = compile_with(
+      r#"<Field label=""><label title=@label><input class="f" @...></label></Field>"#,
+      r#"<Field label="Name" class="wide" id="name" aria-label="n" />"#,
+    );
+    assert_eq!(attributes_of(&output, "label"), pairs(&[("title", "Name")]));
+    assert_eq!(
+      attributes_of(&output, "input"),
+      pairs(&[("aria-label", "n"), ("class", "f wide"), ("id", "name")])
+    );
+    assert_eq!(lints, []);
+  }
+
+  #[test]
+  fn does_not_forward_parameters() {
+    let (output, lints) = compile_with(
+      r#"<Link href=""><a href=@href data-x=@x @...></a></Link>"#,
+      r#"<Link href="/a" x="1" title="t" />"#,
+    );
+    assert_eq!(
+      attributes_of(&output, "a"),
+      pairs(&[("data-x", "1"), ("href", "/a"), ("title", "t")])
+    );
+    assert_eq!(lints, []);
+  }
+
+  #[test]
+  fn merges_styling_onto_every_root_without_a_spread() {
+    let (output, lints) = compile_with(
+      r#"<Pair><b class="x" style="color: red"></b><i></i></Pair>"#,
+      r#"<Pair class="y x" style="margin: 0" id="p" />"#,
+    );
+    assert_eq!(
+      attributes_of(&output, "b"),
+      pairs(&[("class", "x y"), ("style", "color: red; margin: 0")])
+    );
+    assert_eq!(
+      attributes_of(&output, "i"),
+      pairs(&[("class", "y x"), ("style", "margin: 0")])
+    );
+    assert_eq!(lints, [Lint::UnknownParameter]);
+  }
+
+  #[test]
+  fn only_places_fragments_the_template_takes() {
+    let tweet = "<Tweet><h2>@icon</h2><p>@rustlang</p><p> @@icon </p></Tweet>";
+    let (output, lints) = compile_with(
+      tweet,
+      "<Tweet><@fragment name=icon><i></i></@fragment></Tweet>",
+    );
+    assert_eq!(output, "<h2><i></i></h2><p>@rustlang</p><p> @icon </p>");
+    assert_eq!(lints, []);
+    let (output, lints) = compile_with(tweet, "<Tweet />");
+    assert_eq!(output, "<h2>@icon</h2><p>@rustlang</p><p> @icon </p>");
+    assert_eq!(lints, []);
+
+    let card = r#"<Card icon=""><h2>@icon</h2></Card>"#;
+    let (output, lints) = compile_with(card, "<Card />");
+    assert_eq!(output, "<h2></h2>");
+    assert_eq!(lints, [Lint::MissingParameter]);
   }
 }
@@ -0,0 +1,554 @@
+use std::{
+  collections::HashMap, io::IsTerminal, path::Path, rc::Rc, str::FromStr,
+};
+
+use crate::{
+  lint::{Level, Warning},
+  parse::Span,
+  trace::{Error, ErrorKind, Note},
+};
+
+const RED: &str = "\x1b[1;31m";
+const GREEN: &str = "\x1b[1;32m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders errors like a compiler does, quoting the offending
+/// source line under its location. Sources are read from disk
+/// the first time a span points into them
+pub struct Renderer {
+  color: bool,
+  sources: HashMap<Rc<Path>, Option<String>>,
+}
+
+impl Renderer {
+  pub fn new(color: bool) -> Self {
+    Self {
+      color,
+      sources: HashMap::new(),
+    }
+  }
+
+  /// A renderer that only uses color when stderr is a terminal
+  pub fn for_stderr() -> Self {
+    Self::new(std::io::stderr().is_terminal())
+  }
+
+  fn paint(&self, style: &str, text: &str) -> String {
+    if self.color {
+      format!("{}{}{}", style, text, RESET)
+    } else {
+      text.to_string()
+    }
+  }
+
+  /// The line containing the start of `span` and the byte offset
+  /// of the span within it, if its source can be read
+  fn source_line(&mut self, span: &Span) -> Option<(String, usize)> {
+    let file = span.file.as_ref()?;
+    let source = self
+      .sources
+      .entry(file.clone())
+      .or_insert_with(|| std::fs::read_to_string(file).ok())
+      .as_deref()?;
+    let start = source.get(..span.start)?.rfind('\n').map_or(0, |i| i + 1);
+    let end = source[start..]
+      .find('\n')
+      .map_or(source.len(), |i| start + i);
+    let line = source[start..end].trim_end_matches('\r');
+    Some((line.to_string(), (span.start - start).min(line.len())))
+  }
+
+  fn snippet(
+    &mut self,
+    output: &mut String,
+    span: &Span,
+    gutter: usize,
+    underline: &str,
+  ) {
+    let arrow = self.paint(BLUE, "-->");
+    output.push_str(&format!("{:gutter$}{} {}\n", "", arrow, span));
+    let Some((line, offset)) = self.source_line(span) else {
+      return;
+    };
+    let bar = self.paint(BLUE, "|");
+    let number = self.paint(BLUE, &format!("{:>gutter$}", span.line));
+    // Keep tabs so the underline lines up with the source
+    let indent: String = line[..offset]
+      .chars()
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect();
+    let end = (offset + span.len()).min(line.len());
+    let width = line[offset..end].chars().count().max(1);
+    let carets = self.paint(underline, &"^".repeat(width));
+    output.push_str(&format!("{:gutter$} {}\n", "", bar));
+    output.push_str(&format!("{} {} {}\n", number, bar, line));
+    output.push_str(&format!("{:gutter$} {} {}{}\n", "", bar, indent, carets));
+  }
+
+  fn render_parts(
+    &mut self,
+    style: &str,
+    header: &str,
+    message: &str,
+    span: Option<&Span>,
+    notes: &[Note],
+  ) -> String {
+    let gutter = span
+      .into_iter()
+      .chain(notes.iter().filter_map(|note| note.span.as_ref()))
+      .map(|span| span.line.to_string().len())
+      .max()
+      .unwrap_or(0);
+    let mut output = String::new();
+    output.push_str(&format!(
+      "{}{}\n",
+      self.paint(style, header),
+      self.paint(BOLD, &format!(": {}", message))
+    ));
+    if let Some(span) = span {
+      self.snippet(&mut output, span, gutter, style);
+    }
+    for note in notes {
+      match &note.span {
+        Some(span) => {
+          output.push_str(&format!(
+            "{}: {}\n",
+            self.paint(GREEN, "note"),
+            note.message
+          ));
+          self.snippet(&mut output, span, gutter, GREEN);
+        },
+        None => {
+          let equals = self.paint(BLUE, "=");
+          output.push_str(&format!(
+            "{:gutter$} {} {}: {}\n",
+            "",
+            equals,
+            self.paint(BOLD, "note"),
+            note.message
+          ));
+        },
+      }
+    }
+    output
+  }
+
+  pub fn render(&mut self, error: &Error) -> String {
+    let header = format!("error[{}]", error.kind.to_string().to_lowercase());
+    self.render_parts(
+      RED,
+      &header,
+      &error.reason,
+      error.span.as_ref(),
+      &error.backtrace,
+    )
+  }
+
+  /// Renders `warning` as an error when its lint is denied
+  pub fn render_warning(&mut self, warning: &Warning) -> String {
+    let (style, level) = match warning.level {
+      Level::Deny => (RED, "error"),
+      _ => (YELLOW, "warning"),
+    };
+    self.render_parts(
+      style,
+      &format!("{}[{}]", level, warning.lint),
+      &warning.message,
+      warning.span.as_ref(),
+      &warning.notes,
+    )
+  }
+}
+
+/// How diagnostics are written, chosen with `--message-format`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageFormat {
+  /// Source snippets for people, written to stderr
+  #[default]
+  Human,
+  /// One JSON object per line, written to stdout
+  Json,
+  /// GitHub Actions workflow commands, written to stdout so they
+  /// show up as annotations on the changed lines
+  Github,
+}
+
+impl FromStr for MessageFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Error> {
+    match s {
+      "human" => Ok(Self::Human),
+      "json" => Ok(Self::Json),
+      "github" => Ok(Self::Github),
+      _ => Err(Error::new(
+        ErrorKind::Unknown,
+        format!(
+          "unknown message format `{}`, expected human, json or github",
+          s
+        ),
+      )),
+    }
+  }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+  let mut output = String::with_capacity(s.len() + 2);
+  output.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => output.push_str("\\\""),
+      '\\' => output.push_str("\\\\"),
+      '\n' => output.push_str("\\n"),
+      '\r' => output.push_str("\\r"),
+      '\t' => output.push_str("\\t"),
+      c if (c as u32) < 0x20 => {
+        output.push_str(&format!("\\u{:04x}", c as u32))
+      },
+      c => output.push(c),
+    }
+  }
+  output.push('"');
+  output
+}
+
+fn json_span(span: Option<&Span>) -> String {
+  let Some(span) = span else {
+    return "null".to_string();
+  };
+  let file = match &span.file {
+    Some(file) => json_string(&file.to_string_lossy()),
+    None => "null".to_string(),
+  };
+  format!(
+    r#"{{"file":{},"start":{},"end":{},"line":{},"column":{}}}"#,
+    file, span.start, span.end, span.line, span.column
+  )
+}
+
+fn json_diagnostic(
+  level: &str,
+  kind: (&str, &str),
+  message: &str,
+  span: Option<&Span>,
+  notes: &[Note],
+) -> String {
+  let backtrace = notes
+    .iter()
+    .map(|note| {
+      format!(
+        r#"{{"message":{},"span":{}}}"#,
+        json_string(&note.message),
+        json_span(note.span.as_ref())
+      )
+    })
+    .collect::<Vec<_>>()
+    .join(",");
+  format!(
+    r#"{{"level":{},{}:{},"message":{},"span":{},"backtrace":[{}]}}"#,
+    json_string(level),
+    json_string(kind.0),
+    json_string(kind.1),
+    json_string(message),
+    json_span(span),
+    backtrace
+  )
+}
+
+/// `error` as a single line of JSON, with its expansion
+/// backtrace innermost first:
+///
+/// ```json
+/// {"level":"error","kind":"compilation","message":"...",
+///  "span":{"file":"a.html","start":0,"end":8,"line":1,"column":1},
+///  "backtrace":[{"message":"...","span":null}]}
+/// ```
+pub fn render_json(error: &Error) -> String {
+  json_diagnostic(
+    "error",
+    ("kind", &error.kind.to_string().to_lowercase()),
+    &error.reason,
+    error.span.as_ref(),
+    &error.backtrace,
+  )
+}
+
+/// `warning` as a single line of JSON, shaped like an error but
+/// with the lint `code` in place of the `kind`, and a level of
+/// `error` when the lint is denied
+pub fn render_warning_json(warning: &Warning) -> String {
+  json_diagnostic(
+    level_name(warning.level),
+    ("code", warning.lint.code()),
+    &warning.message,
+    warning.span.as_ref(),
+    &warning.notes,
+  )
+}
+
+fn level_name(level: Level) -> &'static str {
+  match level {
+    Level::Deny => "error",
+    _ => "warning",
+  }
+}
+
+fn github_escape(s: &str) -> String {
+  s.replace('%', "%25")
+    .replace('\r', "%0D")
+    .replace('\n', "%0A")
+}
+
+fn github_property(s: &str) -> String {
+  github_escape(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+fn github_command(
+  command: &str,
+  title: &str,
+  message: &str,
+  span: Option<&Span>,
+  notes: &[Note],
+) -> String {
+  let mut properties = vec![];
+  if let Some(span) = span {
+    if let Some(file) = &span.file {
+      let file = github_property(&file.to_string_lossy());
+      properties.push(format!("file={}", file));
+    }
+    properties.push(format!("line={}", span.line));
+    properties.push(format!("col={}", span.column));
+  }
+  properties.push(format!("title={}", github_property(title)));
+  let mut message = message.to_string();
+  for note in notes {
+    message.push_str(&format!("\nnote: {}", note));
+  }
+  format!(
+    "::{} {}::{}",
+    command,
+    properties.join(","),
+    github_escape(&message)
+  )
+}
+
+/// `error` as a GitHub Actions `::error` workflow command, with
+/// the backtrace on the lines after the message
+pub fn render_github(error: &Error) -> String {
+  let title = format!("{} error", error.kind.to_string().to_lowercase());
+  github_command(
+    "error",
+    &title,
+    &error.reason,
+    error.span.as_ref(),
+    &error.backtrace,
+  )
+}
+
+/// `warning` as a `::warning` workflow command, or `::error`
+/// when its lint is denied
+pub fn render_warning_github(warning: &Warning) -> String {
+  github_command(
+    level_name(warning.level),
+    warning.lint.code(),
+    &warning.message,
+    warning.span.as_ref(),
+    &warning.notes,
+  )
+}
+
+/// Writes diagnostics in the chosen format
+pub struct Emitter {
+  format: MessageFormat,
+  renderer: Renderer,
+}
+
+impl Emitter {
+  pub fn new(format: MessageFormat) -> Self {
+    Self {
+      format,
+      renderer: Renderer::for_stderr(),
+    }
+  }
+
+  pub fn emit(&mut self, error: &Error) {
+    match self.format {
+      MessageFormat::Human => eprintln!("{}", self.renderer.render(error)),
+      MessageFormat::Json => println!("{}", render_json(error)),
+      MessageFormat::Github => println!("{}", render_github(error)),
+    }
+  }
+
+  pub fn emit_warning(&mut self, warning: &Warning) {
+    match self.format {
+      MessageFormat::Human => {
+        eprintln!("{}", self.renderer.render_warning(warning))
+      },
+      MessageFormat::Json => println!("{}", render_warning_json(warning)),
+      MessageFormat::Github => {
+        println!("{}", render_warning_github(warning))
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lint::Lint;
+
+  /// The span of the first `needle` in `source`, read from `file`
+  fn span_of(file: &str, source: &str, needle: &str) -> Span {
+    let start = source.find(needle).unwrap();
+    let before = &source[..start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Span {
+      file: Some(Path::new(file).into()),
+      start,
+      end: start + needle.len(),
+      line: before.matches('\n').count() + 1,
+      column: before[line_start..].chars().count() + 1,
+    }
+  }
+
+  /// A renderer without color that reads `source` for `file`
+  fn renderer(file: &str, source: &str) -> Renderer {
+    let mut renderer = Renderer::new(false);
+    renderer
+      .sources
+      .insert(Path::new(file).into(), Some(source.to_string()));
+    renderer
+  }
+
+  fn error_at(span: Span) -> Error {
+    Error::new(ErrorKind::Compilation, "bad tag").at(&span)
+  }
+
+  #[test]
+  fn underlines_the_span() {
+    let source = "<div>\n  <Cardd x=1 />\n</div>";
+    let span = span_of("page.html", source, "<Cardd x=1 />");
+    let output = renderer("page.html", source).render(&error_at(span));
+    assert_eq!(
+      output,
+      "error[compilation]: bad tag\n \
+       --> page.html:2:3\n  \
+       |\n\
+       2 |   <Cardd x=1 />\n  \
+       |   ^^^^^^^^^^^^^\n"
+    );
+  }
+
+  #[test]
+  fn keeps_tabs_in_the_indentation() {
+    let source = "<ul>\n\t\t<li>x</li>\n</ul>";
+    let span = span_of("page.html", source, "<li>");
+    let output = renderer("page.html", source).render(&error_at(span));
+    assert!(
+      output.ends_with("2 | \t\t<li>x</li>\n  | \t\t^^^^\n"),
+      "{}",
+      output
+    );
+  }
+
+  #[test]
+  fn counts_columns_in_characters() {
+    let source = "<p>h\u{e9}llo w\u{f6}rld</p>";
+    let span = span_of("page.html", source, "w\u{f6}rld");
+    assert_eq!(span.column, 10);
+    let output = renderer("page.html", source).render(&error_at(span));
+    assert!(output.contains(" --> page.html:1:10\n"), "{}", output);
+    assert!(output.ends_with(&format!("  | {}^^^^^\n", " ".repeat(9))));
+  }
+
+  #[test]
+  fn clamps_the_underline_to_the_line() {
+    let source = "<div>\n<p>a</p>\n</div>";
+    let mut span = span_of("page.html", source, "<div>\n<p>");
+    let output = renderer("page.html", source).render(&error_at(span.clone()));
+    assert!(output.ends_with("1 | <div>\n  | ^^^^^\n"), "{}", output);
+    span.end = span.start;
+    let output = renderer("page.html", source).render(&error_at(span));
+    assert!(output.ends_with("  | ^\n"), "{}", output);
+  }
+
+  #[test]
+  fn only_colors_when_asked_to() {
+    let source = "<b>";
+    let span = span_of("page.html", source, "<b>");
+    let plain = renderer("page.html", source).render(&error_at(span.clone()));
+    assert!(!plain.contains('\x1b'));
+    let mut colored = renderer("page.html", source);
+    colored.color = true;
+    let output = colored.render(&error_at(span));
+    assert!(output.starts_with(&format!("{}error[compilation]{}", RED, RESET)));
+    assert!(output.contains(&format!("{}^^^{}", RED, RESET)));
+  }
+
+  #[test]
+  fn skips_the_snippet_when_the_source_is_missing() {
+    let span = span_of("missing.html", "<b>", "<b>");
+    let output = Renderer::new(false).render(&error_at(span));
+    assert_eq!(
+      output,
+      "error[compilation]: bad tag\n --> missing.html:1:1\n"
+    );
+  }
+
+  fn span_at(file: Option<&str>, line: usize, column: usize) -> Span {
+    Span {
+      file: file.map(|file| Path::new(file).into()),
+      start: 4,
+      end: 9,
+      line,
+      column,
+    }
+  }
+
+  #[test]
+  fn escapes_json_strings() {
+    assert_eq!(
+      json_string("say \"hi\"\\\n\r\t\u{1}é"),
+      r#""say \"hi\"\\\n\r\t\u0001é""#
+    );
+  }
+
+  #[test]
+  fn renders_json_lines() {
+    let error = Error::new(ErrorKind::Parsing, "bad \"tag\"")
+      .at(&span_at(Some("a\\b.html"), 2, 3))
+      .note_at("in `Card`", &span_at(None, 1, 1));
+    assert_eq!(
+      render_json(&error),
+      r#"{"level":"error","kind":"parsing","message":"bad \"tag\"","span":{"file":"a\\b.html","start":4,"end":9,"line":2,"column":3},"backtrace":[{"message":"in `Card`","span":{"file":null,"start":4,"end":9,"line":1,"column":1}}]}"#
+    );
+    let mut warning = Warning::new(Lint::UnknownComponent, "no\nmatch");
+    assert_eq!(
+      render_warning_json(&warning),
+      r#"{"level":"warning","code":"unknown-component","message":"no\nmatch","span":null,"backtrace":[]}"#
+    );
+    warning.level = Level::Deny;
+    assert!(render_warning_json(&warning).starts_with(r#"{"level":"error","#));
+  }
+
+  #[test]
+  fn escapes_github_commands() {
+    let error = Error::new(ErrorKind::Compilation, "50% done\nnext")
+      .at(&span_at(Some("src/a:b,c.html"), 2, 3))
+      .note_at("in `Card`", &span_at(Some("t.html"), 1, 1));
+    assert_eq!(
+      render_github(&error),
+      "::error file=src/a%3Ab%2Cc.html,line=2,col=3,title=compilation \
+       error::50%25 done%0Anext%0Anote: in `Card` at t.html:1:1"
+    );
+    let warning =
+      Warning::new(Lint::UnusedTemplate, "a, b: c").at(&span_at(None, 1, 1));
+    assert_eq!(
+      render_warning_github(&warning),
+      "::warning line=1,col=1,title=unused-template::a, b: c"
+    );
+  }
+}
@@ -1,31 +1,91 @@
 use crate::parse::Attributes;
 
+/// How the body of a directive is read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectiveBody {
+  /// Kept as written, like the source in `<@code>`
+  Raw,
+  /// Parsed as HTML, so templates can be used inside of it. It
+  /// is expanded and serialized before the directive is
+  Html,
+}
+
+/// How the body of the directive `name` is read. Unknown
+/// directives keep their bodies raw
+pub fn directive_body(name: &str) -> DirectiveBody {
+  match name {
+    "details" | "fragment" => DirectiveBody::Html,
+    _ => DirectiveBody::Raw,
+  }
+}
+
 pub fn expand_directive(
   name: &str,
-  attributes: &Attributes,
+  attributes: &Attributes<'_>,
   contents: &str,
 ) -> String {
   match name {
     "style" => style_dir(attributes).unwrap_or("<!-- -->".to_string()),
     "script" => script_dir(attributes).unwrap_or("<!-- -->".to_string()),
     "code" => code(attributes, contents).unwrap_or("<!-- -->".to_string()),
+    "details" => details(attributes, contents),
     _ => "<!-- -->".to_string(),
   }
 }
 
-fn style_dir(attributes: &Attributes) -> Option<String> {
-  let path = attributes.get("href")?;
+/// Why the directive `name` would expand to an empty comment,
+/// if it would
+pub fn directive_problem(
+  name: &str,
+  attributes: &Attributes<'_>,
+) -> Option<String> {
+  match name {
+    "style" | "script" => {
+      let Some(path) = attributes.get("href") else {
+        return Some(format!("`<@{}>` has no `href` attribute", name));
+      };
+      let error = std::fs::metadata(path.as_ref()).err()?;
+      Some(format!("cannot read `{}`: {}", path, error))
+    },
+    "code" | "details" => None,
+    "fragment" => Some(
+      "`<@fragment>` is only passed on when directly inside of a template \
+       usage"
+        .to_string(),
+    ),
+    _ => Some(format!("unknown directive `<@{}>`", name)),
+  }
+}
+
+fn style_dir(attributes: &Attributes<'_>) -> Option<String> {
+  let path: &str = attributes.get("href")?;
   let file = std::fs::read_to_string(path).ok()?;
   Some(format!("<style>\n{}\n</style>", file.trim()))
 }
 
-fn script_dir(attributes: &Attributes) -> Option<String> {
-  let path = attributes.get("href")?;
+fn script_dir(attributes: &Attributes<'_>) -> Option<String> {
+  let path: &str = attributes.get("href")?;
   let file = std::fs::read_to_string(path).ok()?;
   Some(format!("<script>\n{}\n</script>", file.trim()))
 }
 
-fn code(attributes: &Attributes, contents: &str) -> Option<String> {
+/// A collapsible `<details>` block, with the `summary` attribute
+/// as its always visible title
+fn details(attributes: &Attributes<'_>, contents: &str) -> String {
+  let open = match attributes.get("open") {
+    Some(_) => " open",
+    None => "",
+  };
+  let summary = match attributes.get("summary") {
+    Some(summary) => {
+      format!("<summary>{}</summary>", v_htmlescape::escape(summary))
+    },
+    None => String::new(),
+  };
+  format!("<details{}>{}{}</details>", open, summary, contents.trim())
+}
+
+fn code(attributes: &Attributes<'_>, contents: &str) -> Option<String> {
   use inkjet::*;
   let minimum_indent = contents
     .trim()
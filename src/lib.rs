@@ -0,0 +1,13 @@
+pub mod assets;
+pub mod compile;
+pub mod diagnostic;
+pub mod directives;
+mod entities;
+pub mod lint;
+pub mod params;
+pub mod parse;
+pub mod scope;
+pub mod select;
+pub mod trace;
+pub mod transform;
+pub mod tree;
@@ -0,0 +1,276 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::{
+  parse::Span,
+  trace::{Error, ErrorKind, Note},
+};
+
+/// A named kind of warning, which can be allowed or denied by
+/// its code, as in `-D missing-parameter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+  /// Text or other non-element nodes between template definitions
+  UnexpectedRootNode,
+  /// A template uses `@name` but the usage does not set `name`
+  MissingParameter,
+  /// A usage has children but its template has no `<@children />`
+  IgnoredChildren,
+  /// A directive that is unknown or whose file cannot be read,
+  /// which is replaced by an empty comment
+  UnresolvedDirective,
+  /// A template that no page uses
+  UnusedTemplate,
+  /// A capitalized tag that matches no template
+  UnknownComponent,
+  /// A usage sets an attribute its template neither uses nor
+  /// declares, so it is dropped
+  UnknownParameter,
+  /// A usage sets a parameter to a value its declared type does
+  /// not accept
+  InvalidParameter,
+}
+
+impl Lint {
+  pub const ALL: [Lint; 8] = [
+    Lint::UnexpectedRootNode,
+    Lint::MissingParameter,
+    Lint::IgnoredChildren,
+    Lint::UnresolvedDirective,
+    Lint::UnusedTemplate,
+    Lint::UnknownComponent,
+    Lint::UnknownParameter,
+    Lint::InvalidParameter,
+  ];
+
+  pub fn code(self) -> &'static str {
+    match self {
+      Lint::UnexpectedRootNode => "unexpected-root-node",
+      Lint::MissingParameter => "missing-parameter",
+      Lint::IgnoredChildren => "ignored-children",
+      Lint::UnresolvedDirective => "unresolved-directive",
+      Lint::UnusedTemplate => "unused-template",
+      Lint::UnknownComponent => "unknown-component",
+      Lint::UnknownParameter => "unknown-parameter",
+      Lint::InvalidParameter => "invalid-parameter",
+    }
+  }
+
+  pub fn default_level(self) -> Level {
+    match self {
+      Lint::InvalidParameter => Level::Deny,
+      _ => Level::Warn,
+    }
+  }
+}
+
+impl Display for Lint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.code())
+  }
+}
+
+impl FromStr for Lint {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Error> {
+    Lint::ALL
+      .into_iter()
+      .find(|lint| lint.code() == s)
+      .ok_or_else(|| {
+        Error::new(ErrorKind::Unknown, format!("unknown lint `{}`", s))
+      })
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+  /// The warning is dropped
+  Allow,
+  /// The warning is reported
+  Warn,
+  /// The warning is reported as an error and fails the build
+  Deny,
+}
+
+/// A problem that does not stop compilation
+#[derive(Clone, Debug)]
+pub struct Warning {
+  pub lint: Lint,
+  /// The level set for `lint` when the warning was reported
+  pub level: Level,
+  pub message: String,
+  pub span: Option<Span>,
+  /// Context such as the template usage being expanded,
+  /// innermost first
+  pub notes: Vec<Note>,
+}
+
+impl Warning {
+  pub fn new(lint: Lint, message: impl Into<String>) -> Self {
+    Self {
+      lint,
+      level: lint.default_level(),
+      message: message.into(),
+      span: None,
+      notes: vec![],
+    }
+  }
+
+  pub fn at(mut self, span: &Span) -> Self {
+    self.span = Some(span.clone());
+    self
+  }
+
+  pub fn note(mut self, note: Note) -> Self {
+    self.notes.push(note);
+    self
+  }
+}
+
+/// The level of every lint, set from the command line with
+/// `-A`, `-W` and `-D` followed by a lint code, or `warnings`
+/// for every lint that warns by default
+#[derive(Clone, Debug, Default)]
+pub struct LintConfig {
+  levels: HashMap<Lint, Level>,
+  warnings: Option<Level>,
+}
+
+impl LintConfig {
+  pub fn set(&mut self, code: &str, level: Level) -> Result<(), Error> {
+    if code == "warnings" {
+      self.warnings = Some(level);
+    } else {
+      self.levels.insert(code.parse()?, level);
+    }
+    Ok(())
+  }
+
+  pub fn level(&self, lint: Lint) -> Level {
+    if let Some(level) = self.levels.get(&lint) {
+      return *level;
+    }
+    match (lint.default_level(), self.warnings) {
+      (Level::Warn, Some(level)) => level,
+      (level, _) => level,
+    }
+  }
+
+  /// Sets the level of each warning, dropping allowed ones
+  pub fn apply(&self, warnings: Vec<Warning>) -> Vec<Warning> {
+    warnings
+      .into_iter()
+      .filter_map(|mut warning| {
+        warning.level = self.level(warning.lint);
+        (warning.level != Level::Allow).then_some(warning)
+      })
+      .collect()
+  }
+}
+
+/// The number of single character insertions, deletions and
+/// substitutions needed to turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + usize::from(ca != *cb);
+      current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+/// The candidate closest to `name`, if any is close enough to be
+/// a likely typo
+pub fn suggest<'c>(
+  name: &str,
+  candidates: impl IntoIterator<Item = &'c str>,
+) -> Option<&'c str> {
+  let limit = (name.chars().count() / 3).max(1);
+  candidates
+    .into_iter()
+    .map(|candidate| (edit_distance(name, candidate), candidate))
+    .filter(|(distance, _)| *distance <= limit)
+    .min()
+    .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn configured(levels: &[(&str, Level)]) -> LintConfig {
+    let mut config = LintConfig::default();
+    for (code, level) in levels {
+      config.set(code, *level).unwrap();
+    }
+    config
+  }
+
+  #[test]
+  fn uses_default_levels() {
+    let config = LintConfig::default();
+    assert_eq!(config.level(Lint::UnusedTemplate), Level::Warn);
+    assert_eq!(config.level(Lint::InvalidParameter), Level::Deny);
+  }
+
+  #[test]
+  fn sets_levels_by_code() {
+    let config = configured(&[
+      ("unused-template", Level::Allow),
+      ("invalid-parameter", Level::Warn),
+    ]);
+    assert_eq!(config.level(Lint::UnusedTemplate), Level::Allow);
+    assert_eq!(config.level(Lint::InvalidParameter), Level::Warn);
+    assert_eq!(config.level(Lint::MissingParameter), Level::Warn);
+    let error = LintConfig::default()
+      .set("unused", Level::Deny)
+      .unwrap_err();
+    assert_eq!(error.reason, "unknown lint `unused`");
+  }
+
+  #[test]
+  fn sets_every_warning_with_warnings() {
+    let config = configured(&[("warnings", Level::Deny)]);
+    assert_eq!(config.level(Lint::UnusedTemplate), Level::Deny);
+    // Only lints that warn by default are affected
+    let config = configured(&[("warnings", Level::Allow)]);
+    assert_eq!(config.level(Lint::UnusedTemplate), Level::Allow);
+    assert_eq!(config.level(Lint::InvalidParameter), Level::Deny);
+    // A lint named on its own wins, whichever comes first
+    let config = configured(&[
+      ("unknown-component", Level::Deny),
+      ("warnings", Level::Allow),
+    ]);
+    assert_eq!(config.level(Lint::UnknownComponent), Level::Deny);
+  }
+
+  #[test]
+  fn drops_allowed_warnings() {
+    let config = configured(&[
+      ("unused-template", Level::Allow),
+      ("missing-parameter", Level::Deny),
+    ]);
+    let warnings = config.apply(vec![
+      Warning::new(Lint::UnusedTemplate, "a"),
+      Warning::new(Lint::MissingParameter, "b"),
+      Warning::new(Lint::UnknownComponent, "c"),
+    ]);
+    let levels: Vec<_> = warnings
+      .iter()
+      .map(|warning| (warning.message.as_str(), warning.level))
+      .collect();
+    assert_eq!(levels, [("b", Level::Deny), ("c", Level::Warn)]);
+  }
+
+  #[test]
+  fn parses_every_code() {
+    for lint in Lint::ALL {
+      assert_eq!(lint.code().parse::<Lint>().unwrap(), lint);
+    }
+  }
+}
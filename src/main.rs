@@ -1,11 +1,87 @@
-mod compile;
-mod directives;
-mod parse;
-mod trace;
+use std::process::ExitCode;
 
-fn main() -> Result<(), trace::Error> {
+use diagnostic::MessageFormat;
+use html::{compile, diagnostic, lint::Level, parse::ParseMode, trace};
+use trace::WithContext;
+
+fn lint_level(arg: &str) -> Option<Level> {
+  match arg.get(..2)? {
+    "-A" => Some(Level::Allow),
+    "-W" => Some(Level::Warn),
+    "-D" => Some(Level::Deny),
+    _ => None,
+  }
+}
+
+fn run(
+  c: &mut compile::Compiler,
+  format: &mut MessageFormat,
+) -> Result<(), trace::Error> {
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if let Some(level) = lint_level(&arg) {
+      // Accept both `-D code` and `-Dcode`
+      let code = match &arg[2..] {
+        "" => args.next().ctx(format!("expected a lint after {}", arg))?,
+        code => code.to_string(),
+      };
+      c.with_lint_level(&code, level)?;
+      continue;
+    }
+    match arg.as_str() {
+      "--transforms" => {
+        let path = args.next().ctx("expected a file after --transforms")?;
+        c.with_transforms_file(path)?;
+      },
+      "--fingerprint" => {
+        c.with_fingerprinting();
+      },
+      // Accepts HTML that leaves out optional end tags, such as
+      // pages written by hand or taken from elsewhere
+      "--tolerant" => {
+        c.with_parse_mode(ParseMode::Tolerant);
+      },
+      "--message-format" => {
+        *format = args
+          .next()
+          .ctx("expected a format after --message-format")?
+          .parse()?;
+      },
+      _ if arg.starts_with("--message-format=") => {
+        *format = arg["--message-format=".len()..].parse()?;
+      },
+      _ => {
+        return Err(trace::Error::new(
+          trace::ErrorKind::Unknown,
+          format!("unknown argument {}", arg),
+        ))
+      },
+    }
+  }
+  c.with_template_folder("templates/")?;
+  // Pages that fail are reported once the whole folder is done,
+  // so they do not hide unused templates
+  let compiled = c.with_src_folder("hyper-src/", "hyper-build/").map(|_| ());
+  let checked = c.check_unused_templates().map(|_| ());
+  compiled.and(checked)
+}
+
+fn main() -> ExitCode {
   let mut c = compile::Compiler::new();
-  c.with_template_folder("templates/")?
-    .with_src_folder("hyper-src/", "hyper-build/")?;
-  Ok(())
+  let mut format = MessageFormat::default();
+  let result = run(&mut c, &mut format);
+  let mut emitter = diagnostic::Emitter::new(format);
+  for warning in c.warnings() {
+    emitter.emit_warning(warning);
+  }
+  for error in c.errors() {
+    emitter.emit(error);
+  }
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(error) => {
+      emitter.emit(&error);
+      ExitCode::FAILURE
+    },
+  }
 }
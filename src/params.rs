@@ -0,0 +1,401 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+  parse::{Attributes, HtmlElement},
+  trace::*,
+  tree::*,
+};
+
+/// Marks an element in a template body that receives every
+/// attribute of a usage that is not one of the template's
+/// parameters, as in `<a class="link" href=@href @...>`
+pub const SPREAD_MARKER: &str = "@...";
+
+/// The type of a template parameter, declared as an attribute
+/// of the template definition:
+///
+/// ```html
+/// <Button href="url" size="number" kind="enum(primary|danger)">
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParamType {
+  String,
+  Url,
+  Number,
+  Enum(Vec<String>),
+}
+
+impl ParamType {
+  pub fn parse(declaration: &str) -> Option<Self> {
+    match declaration.trim() {
+      "" | "string" => Some(Self::String),
+      "url" => Some(Self::Url),
+      "number" => Some(Self::Number),
+      other => {
+        let variants = other.strip_prefix("enum(")?.strip_suffix(')')?;
+        let variants = variants
+          .split('|')
+          .map(|variant| variant.trim().to_string())
+          .collect();
+        Some(Self::Enum(variants))
+      },
+    }
+  }
+
+  /// Whether `value` is valid for this type
+  pub fn accepts(&self, value: &str) -> bool {
+    match self {
+      Self::String => true,
+      Self::Url => {
+        !value.is_empty()
+          && !value
+            .chars()
+            .any(|c| c.is_whitespace() || "<>\"'`".contains(c))
+      },
+      Self::Number => value.trim().parse::<f64>().is_ok_and(f64::is_finite),
+      Self::Enum(variants) => variants.iter().any(|variant| variant == value),
+    }
+  }
+}
+
+impl Display for ParamType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::String => write!(f, "string"),
+      Self::Url => write!(f, "url"),
+      Self::Number => write!(f, "number"),
+      Self::Enum(variants) => write!(f, "enum({})", variants.join("|")),
+    }
+  }
+}
+
+/// The parameters a template definition declares with their
+/// types. Attributes starting with `@`, such as `@override`, are
+/// markers rather than declarations
+pub fn declared_parameters<'t>(
+  template: &'t Element<'_>,
+) -> Result<Vec<(&'t str, ParamType)>> {
+  let mut declared = vec![];
+  for (name, declaration) in &template.attributes {
+    if name.starts_with('@') {
+      continue;
+    }
+    let param_type = ParamType::parse(declaration).ok_or_else(|| {
+      compile_error(format!(
+        "unknown type `{}` for parameter `{}`, expected string, url, \
+         number or enum(a|b)",
+        declaration, name
+      ))
+      .at(&template.span)
+    })?;
+    declared.push((name.as_ref(), param_type));
+  }
+  Ok(declared)
+}
+
+/// The parameter an attribute value in a template's body refers
+/// to, as in `href=@href`. A value starting with `@@` is instead
+/// a literal one starting with `@`
+pub fn parameter_reference(value: &str) -> Option<&str> {
+  value
+    .strip_prefix('@')
+    .filter(|name| !name.starts_with('@'))
+}
+
+/// Every parameter a template's body uses as an `@name`
+/// attribute value
+pub fn referenced_parameters<'t>(
+  template: &'t Element<'_>,
+) -> HashSet<&'t str> {
+  template
+    .descendants()
+    .filter_map(Node::as_element)
+    .flat_map(|element| element.attributes.values())
+    .filter_map(|value| parameter_reference(value))
+    .collect()
+}
+
+/// The name of the fragment that text in a template's body
+/// places, if the text is only `@name`, as in
+///
+/// ```html
+/// <Card><h2>@icon <@children /></h2></Card>
+/// ```
+///
+/// The text is only replaced when the usage passes that fragment
+/// or the template declares `name`, so other text such as
+/// `@rustlang` is kept as written
+pub fn fragment_reference(text: &str) -> Option<&str> {
+  let name = text.trim().strip_prefix('@')?;
+  let is_name = !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+  is_name.then_some(name)
+}
+
+/// Text that is only `@@name` is written as `@name` rather than
+/// placing a fragment. Returns the text with one `@` removed
+pub fn escaped_fragment_reference(text: &str) -> Option<String> {
+  fragment_reference(text.trim_start().strip_prefix('@')?)?;
+  Some(text.replacen("@@", "@", 1))
+}
+
+/// Every fragment a template's body places
+pub fn referenced_fragments<'t>(template: &'t Element<'_>) -> HashSet<&'t str> {
+  template
+    .descendants()
+    .filter_map(|node| match node {
+      Node::Leaf(HtmlElement::Text(text), _) => fragment_reference(text),
+      _ => None,
+    })
+    .collect()
+}
+
+/// Whether any element in a template's body spreads the
+/// attributes of its usages
+pub fn has_spread(template: &Element<'_>) -> bool {
+  template
+    .descendants()
+    .filter_map(Node::as_element)
+    .any(|element| element.has_attribute(SPREAD_MARKER))
+}
+
+/// The attributes of `usage` that are not parameters of
+/// `template`, which are forwarded to elements that spread them
+pub fn forwarded_attributes<'a>(
+  usage: &Element<'a>,
+  template: &Element<'_>,
+) -> Attributes<'a> {
+  let referenced = referenced_parameters(template);
+  let declared = declared_parameters(template).unwrap_or_default();
+  usage
+    .attributes
+    .iter()
+    .filter(|(key, _)| {
+      !referenced.contains(key.as_ref())
+        && !declared.iter().any(|(name, _)| *name == key.as_ref())
+    })
+    .map(|(key, value)| (key.clone(), value.clone()))
+    .collect()
+}
+
+/// Attributes whose values from a usage are combined with the
+/// template's instead of replacing them
+pub const MERGED_ATTRIBUTES: [&str; 2] = ["class", "style"];
+
+/// Combines a template's `class` with a usage's, keeping each
+/// class once, in order
+fn merge_class(template: &str, usage: &str) -> String {
+  let mut classes: Vec<&str> = template.split_whitespace().collect();
+  for class in usage.split_whitespace() {
+    if !classes.contains(&class) {
+      classes.push(class);
+    }
+  }
+  classes.join(" ")
+}
+
+/// Appends a usage's `style` declarations to a template's, so
+/// the usage's win where both set the same property
+fn merge_style(template: &str, usage: &str) -> String {
+  let template = template.trim().trim_end_matches(';');
+  let usage = usage.trim();
+  match (template.is_empty(), usage.is_empty()) {
+    (true, _) => usage.to_string(),
+    (_, true) => template.to_string(),
+    _ => format!("{}; {}", template, usage),
+  }
+}
+
+/// Adds the attributes of a usage to those of a template
+/// element. `class` and `style` are merged, and any other
+/// attribute of the usage replaces the template's
+pub fn merge_attributes<'a>(into: &mut Attributes<'a>, from: &Attributes<'a>) {
+  for (key, value) in from {
+    let merged = match (key.as_ref(), into.get(key)) {
+      ("class", Some(old)) => merge_class(old, value).into(),
+      ("style", Some(old)) => merge_style(old, value).into(),
+      _ => value.clone(),
+    };
+    into.insert(key.clone(), merged);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    compile::{compile_source, parse_templates, serialize},
+    lint::{Level, Lint},
+    parse::ParseMode,
+  };
+
+  #[test]
+  fn parses_types() {
+    assert_eq!(ParamType::parse(""), Some(ParamType::String));
+    assert_eq!(ParamType::parse("string"), Some(ParamType::String));
+    assert_eq!(ParamType::parse(" url "), Some(ParamType::Url));
+    assert_eq!(ParamType::parse("number"), Some(ParamType::Number));
+    assert_eq!(
+      ParamType::parse("enum(primary | danger)"),
+      Some(ParamType::Enum(vec!["primary".into(), "danger".into()]))
+    );
+    assert_eq!(ParamType::parse("enum(a"), None);
+    assert_eq!(ParamType::parse("int"), None);
+    let kind = ParamType::parse("enum(a|b)").unwrap();
+    assert_eq!(kind.to_string(), "enum(a|b)");
+  }
+
+  #[test]
+  fn checks_values_against_types() {
+    let url = ParamType::Url;
+    assert!(url.accepts("/home") && url.accepts("https://a.org/?q=1#x"));
+    for bad in ["", "a b", "a\"b", "<a>", "a`b"] {
+      assert!(!url.accepts(bad), "{}", bad);
+    }
+    let number = ParamType::Number;
+    assert!(number.accepts("42") && number.accepts(" -1.5 "));
+    assert!(number.accepts("1e3"));
+    for bad in ["", "abc", "1px", "NaN", "inf"] {
+      assert!(!number.accepts(bad), "{}", bad);
+    }
+    let kind = ParamType::parse("enum(primary|danger)").unwrap();
+    assert!(kind.accepts("danger"));
+    assert!(!kind.accepts("Danger") && !kind.accepts(""));
+    assert!(ParamType::String.accepts(""));
+  }
+
+  #[test]
+  fn reads_fragment_references() {
+    assert_eq!(fragment_reference(" @icon\n"), Some("icon"));
+    assert_eq!(fragment_reference("@@icon"), None);
+    assert_eq!(fragment_reference("@ icon"), None);
+    assert_eq!(fragment_reference("mail @icon"), None);
+    assert_eq!(
+      escaped_fragment_reference(" @@icon").as_deref(),
+      Some(" @icon")
+    );
+    assert_eq!(escaped_fragment_reference("@icon"), None);
+    assert_eq!(escaped_fragment_reference("@@@icon"), None);
+  }
+
+  #[test]
+  fn merges_classes_once_in_order() {
+    assert_eq!(merge_class("a b", "c a  d"), "a b c d");
+    assert_eq!(merge_class("", " b b "), "b");
+    assert_eq!(merge_class("a", ""), "a");
+  }
+
+  #[test]
+  fn joins_styles_with_semicolons() {
+    assert_eq!(
+      merge_style("color: red;", "margin: 0"),
+      "color: red; margin: 0"
+    );
+    assert_eq!(
+      merge_style("color: red", "color: blue;"),
+      "color: red; color: blue;"
+    );
+    assert_eq!(merge_style(" ; ", "margin: 0"), "margin: 0");
+    assert_eq!(merge_style("color: red;", ""), "color: red");
+  }
+
+  #[test]
+  fn usage_attributes_replace_all_but_class_and_style() {
+    let mut into: Attributes = [("class", "a"), ("style", "x: 1"), ("id", "t")]
+      .into_iter()
+      .map(|(k, v)| (k.into(), v.into()))
+      .collect();
+    let from: Attributes = [("class", "b a"), ("style", "y: 2"), ("id", "u")]
+      .into_iter()
+      .map(|(k, v)| (k.into(), v.into()))
+      .collect();
+    merge_attributes(&mut into, &from);
+    assert_eq!(into["class"], "a b");
+    assert_eq!(into["style"], "x: 1; y: 2");
+    assert_eq!(into["id"], "u");
+  }
+
+  /// The lints and levels of the warnings from compiling `source`
+  /// with `templates`
+  fn lints(templates: &str, source: &str) -> Vec<(Lint, Level, String)> {
+    let templates = parse_templates(templates, ParseMode::Strict).unwrap();
+    let (_, warnings) =
+      compile_source(source, &templates.templates, ParseMode::Strict).unwrap();
+    warnings
+      .into_iter()
+      .map(|warning| (warning.lint, warning.level, warning.message))
+      .collect()
+  }
+
+  const BUTTON: &str = r#"<Button href="url" size="number" kind="enum(primary|danger)"><a href=@href class=@kind data-size=@size></a></Button>"#;
+
+  #[test]
+  fn lints_invalid_parameters() {
+    let warnings =
+      lints(BUTTON, r#"<Button href="a b" size="12" kind="big" />"#);
+    assert_eq!(
+      warnings,
+      [
+        (
+          Lint::InvalidParameter,
+          Level::Deny,
+          "`a b` is not a valid url for parameter `href` of `Button`".into()
+        ),
+        (
+          Lint::InvalidParameter,
+          Level::Deny,
+          "`big` is not a valid enum(primary|danger) for parameter `kind` \
+           of `Button`"
+            .into()
+        ),
+      ]
+    );
+    let valid = r#"<Button href="/x" size="2" kind="danger" />"#;
+    assert_eq!(lints(BUTTON, valid), []);
+  }
+
+  #[test]
+  fn lints_unknown_parameters() {
+    let warnings = lints(
+      "<Card title=\"\"><h2 title=@title></h2></Card><Used><b x=@used></b></Used>",
+      r#"<Card title="t" titel="t" class="c" /><Used used="u" />"#,
+    );
+    // `class` is merged onto the root and `used` is undeclared but placed,
+    // so only the misspelling is reported
+    assert_eq!(
+      warnings,
+      [(
+        Lint::UnknownParameter,
+        Level::Warn,
+        "`Card` does not take a `titel` parameter, so it is dropped".into()
+      )]
+    );
+  }
+
+  #[test]
+  fn rejects_unknown_types() {
+    let error =
+      parse_templates(r#"<Card size="int"></Card>"#, ParseMode::Strict)
+        .err()
+        .unwrap();
+    assert!(
+      error.reason.starts_with("unknown type `int`"),
+      "{}",
+      error.reason
+    );
+    let templates = parse_templates(
+      r#"<Card size="number"><b data-size=@size></b></Card>"#,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    let (nodes, _) = compile_source(
+      "<Card size=\"3\" />",
+      &templates.templates,
+      ParseMode::Strict,
+    )
+    .unwrap();
+    assert_eq!(serialize(&nodes), r#"<b data-size="3"></b>"#);
+  }
+}
@@ -1,32 +1,37 @@
-use std::collections::HashMap;
+use std::{
+  borrow::Cow, collections::HashMap, fmt::Display, path::Path, rc::Rc,
+};
 
-use crate::directives::expand_directive;
+use crate::{
+  directives::{directive_body, expand_directive, DirectiveBody},
+  entities::NAMED_REFERENCES,
+};
 
-pub type Attributes = HashMap<String, String>;
+pub type Attributes<'a> = HashMap<Cow<'a, str>, Cow<'a, str>>;
+/// An attribute name and its value
+pub type Attribute<'a> = (Cow<'a, str>, Cow<'a, str>);
 pub type Offset = usize;
 pub type Parse<'a, T> = (T, &'a str, Offset);
 pub type MaybeParse<'a, T> = Option<Parse<'a, T>>;
 
+/// Consumes bytes until `condition` holds. Conditions only ever
+/// stop on ASCII bytes, so the split is always on a character
+/// boundary
 #[inline]
-fn parse_until(i: &str, condition: impl Fn(char) -> bool) -> Parse<&str> {
-  match i.chars().position(condition) {
+fn parse_until(i: &str, condition: impl Fn(u8) -> bool) -> Parse<'_, &str> {
+  match i.bytes().position(condition) {
     Some(pos) => (&i[..pos], &i[pos..], pos),
-    None => (&i, "", i.len()),
+    None => (i, "", i.len()),
   }
 }
 
+#[inline]
 fn parse_until_str<'a>(
   tail: &'a str,
   to_match: &'static str,
 ) -> MaybeParse<'a, &'a str> {
-  for i in 0..tail.len() {
-    let substr = &tail[0..i];
-    if substr.ends_with(to_match) {
-      let end = i - to_match.len();
-      return Some((&tail[0..end], &tail[end..], end));
-    }
-  }
-  None
+  let end = tail.find(to_match)?;
+  Some((&tail[..end], &tail[end..], end))
 }
 
 #[inline]
@@ -44,36 +49,221 @@ fn parse_str<'a>(i: &'a str, matches: &str) -> MaybeParse<'a, &'a str> {
   }
 }
 
-fn parse_char(i: &str, matches: char) -> MaybeParse<char> {
-  if let Some(c) = i.chars().next() {
-    if c == matches {
-      return Some((c, &i[1..], 1));
-    }
+#[inline]
+fn parse_char(i: &str, matches: u8) -> MaybeParse<'_, u8> {
+  match i.as_bytes().first() {
+    Some(&c) if c == matches => Some((c, &i[1..], 1)),
+    _ => None,
   }
-  None
 }
 
-fn parse_delimited(i: &str, delim: char) -> MaybeParse<&str> {
+fn parse_delimited(i: &str, delim: u8) -> MaybeParse<'_, &str> {
   let (_start, i, o1) = parse_char(i, delim)?;
   let (contents, i, o2) = parse_until(i, |c| c == delim);
   let (_end, i, o3) = parse_char(i, delim)?;
   Some((contents, i, o1 + o2 + o3))
 }
 
-fn index_to_rc(input: &str, index: usize) -> (usize, usize) {
-  let (mut row, mut col) = (1, 1);
-  for c in input[0..index].chars() {
-    if c == '\n' {
-      row += 1;
-      col = 1;
-    } else {
-      col += 1;
+/// Length of the longest reference that can be written without
+/// a `;`
+const LONGEST_LEGACY_REFERENCE: usize = 6;
+
+/// The text a named character reference stands for, where
+/// `name` includes the `;` if it was written with one
+fn named_reference(name: &str) -> Option<&'static str> {
+  let index = NAMED_REFERENCES
+    .binary_search_by(|(candidate, _)| candidate.cmp(&name))
+    .ok()?;
+  Some(NAMED_REFERENCES[index].1)
+}
+
+/// Decodes the character reference at the start of `i`, which
+/// starts with `&`, returning its text and length. Follows the
+/// rules for attribute values, where a legacy reference without
+/// a `;` is kept as written if it runs into more of a name, as in
+/// `?a=1&copy=2`
+fn decode_reference(i: &str) -> Option<(Cow<'static, str>, usize)> {
+  let tail = &i[1..];
+  if let Some(number) = tail.strip_prefix('#') {
+    let (digits, radix) = match number.strip_prefix(['x', 'X']) {
+      Some(hex) => (hex, 16),
+      None => (number, 10),
+    };
+    let length = digits
+      .bytes()
+      .take_while(|c| (*c as char).is_digit(radix))
+      .count();
+    if length == 0 {
+      return None;
+    }
+    // Out of range code points, surrogates and NUL are errors,
+    // which decode to the replacement character
+    let c = u32::from_str_radix(&digits[..length], radix)
+      .ok()
+      .filter(|c| *c != 0)
+      .and_then(char::from_u32)
+      .unwrap_or(char::REPLACEMENT_CHARACTER);
+    let prefix = number.len() - digits.len();
+    let semicolon = usize::from(digits[length..].starts_with(';'));
+    return Some((c.to_string().into(), 2 + prefix + length + semicolon));
+  }
+  let run = tail.bytes().take_while(u8::is_ascii_alphanumeric).count();
+  if tail[run..].starts_with(';') {
+    if let Some(text) = named_reference(&tail[..=run]) {
+      return Some((text.into(), run + 2));
+    }
+  }
+  let length = (1..=run.min(LONGEST_LEGACY_REFERENCE))
+    .rev()
+    .find(|length| named_reference(&tail[..*length]).is_some())?;
+  let next = tail[length..].bytes().next();
+  if next.is_some_and(|c| c == b'=' || c.is_ascii_alphanumeric()) {
+    return None;
+  }
+  Some((named_reference(&tail[..length])?.into(), length + 1))
+}
+
+/// Replaces character references such as `&amp;`, `&eacute;`,
+/// `&#39;` and `&#x27;` with the text they stand for, as a
+/// browser does in attribute values. Anything that is not a
+/// reference is kept, so a stray `&` survives. Only allocates if
+/// there is something to decode.
+pub fn decode_entities(i: &str) -> Cow<'_, str> {
+  if !i.contains('&') {
+    return Cow::Borrowed(i);
+  }
+  let mut output = String::with_capacity(i.len());
+  let mut i = i;
+  while let Some(start) = i.find('&') {
+    output.push_str(&i[..start]);
+    i = &i[start..];
+    match decode_reference(i) {
+      Some((text, length)) => {
+        output.push_str(&text);
+        i = &i[length..];
+      },
+      None => {
+        output.push('&');
+        i = &i[1..];
+      },
+    }
+  }
+  output.push_str(i);
+  Cow::Owned(output)
+}
+
+/// Decodes an attribute value as written in the source. Values
+/// starting with `@` refer to template parameters, so one that
+/// only starts with `@` once decoded, as `&#64;t` does, is kept
+/// literal by escaping it as `@@t`
+fn decode_attribute_value(raw: &str) -> Cow<'_, str> {
+  let decoded = decode_entities(raw);
+  if decoded.starts_with('@') && !raw.starts_with('@') {
+    Cow::Owned(format!("@{}", decoded))
+  } else {
+    decoded
+  }
+}
+
+/// The value an attribute is output with. A value starting
+/// with `@@` is a literal one starting with `@`
+pub fn literal_value(value: &str) -> &str {
+  match value.strip_prefix('@') {
+    Some(rest) if rest.starts_with('@') => rest,
+    _ => value,
+  }
+}
+
+/// Escapes a value so it can be written between double
+/// quotes and decoded back into the same string
+pub fn escape_attribute(i: &str) -> String {
+  let mut output = String::with_capacity(i.len());
+  for c in i.chars() {
+    match c {
+      '&' => output.push_str("&amp;"),
+      '"' => output.push_str("&quot;"),
+      _ => output.push(c),
+    }
+  }
+  output
+}
+
+/// Where a lexeme was found in its source. Nodes expanded from
+/// a template keep the span they have in the template file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+  /// The file the source was read from, if it came from one
+  pub file: Option<Rc<Path>>,
+  /// Byte offset of the first byte
+  pub start: usize,
+  /// Byte offset one past the last byte
+  pub end: usize,
+  /// One-based line of the first byte
+  pub line: usize,
+  /// One-based column of the first byte, counting characters
+  pub column: usize,
+}
+
+impl Span {
+  pub fn len(&self) -> usize {
+    self.end - self.start
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.start == self.end
+  }
+}
+
+impl Display for Span {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file.display())?;
     }
+    write!(f, "{}:{}", self.line, self.column)
   }
-  (row, col)
 }
 
-pub const LEXEME_MEMORY_LIMIT: usize = 65535;
+/// The line and column of a byte offset, advanced forward
+/// through a source so each byte is only counted once
+struct Position {
+  offset: usize,
+  line: usize,
+  column: usize,
+}
+
+impl Position {
+  fn new() -> Self {
+    Self {
+      offset: 0,
+      line: 1,
+      column: 1,
+    }
+  }
+
+  fn advance(&mut self, input: &str, to: usize) {
+    for c in input[self.offset..to].chars() {
+      if c == '\n' {
+        self.line += 1;
+        self.column = 1;
+      } else {
+        self.column += 1;
+      }
+    }
+    self.offset = to;
+  }
+
+  fn span(&self, file: &Option<Rc<Path>>, len: usize) -> Span {
+    Span {
+      file: file.clone(),
+      start: self.offset,
+      end: self.offset + len,
+      line: self.line,
+      column: self.column,
+    }
+  }
+}
+
+pub const LEXEME_MEMORY_LIMIT: usize = 1 << 22;
 
 // Tags that are implicitly self closing, ending in /> is
 // optional
@@ -82,13 +272,171 @@ const VOID_ELEMENTS: [&str; 16] = [
   "keygen", "link", "meta", "param", "source", "track", "wbr",
 ];
 
+// Tags whose contents are never parsed as HTML, besides
+// `style` and `script` which have their own lexemes
+const RAW_TEXT_ELEMENTS: [&str; 5] =
+  ["xmp", "iframe", "noembed", "noframes", "noscript"];
+
+// Tags whose contents are never parsed as HTML, but may contain
+// character references that the browser resolves
+const ESCAPABLE_RAW_TEXT_ELEMENTS: [&str; 2] = ["textarea", "title"];
+
+// Tags whose end tag may be left out in tolerant mode
+const OPTIONAL_END_TAGS: [&str; 19] = [
+  "html", "head", "body", "li", "dt", "dd", "p", "rt", "rp", "optgroup",
+  "option", "colgroup", "caption", "thead", "tbody", "tfoot", "tr", "td", "th",
+];
+
+// Tags that implicitly close an open `<p>`
+const CLOSES_PARAGRAPH: [&str; 38] = [
+  "address",
+  "article",
+  "aside",
+  "blockquote",
+  "center",
+  "details",
+  "dialog",
+  "dir",
+  "div",
+  "dl",
+  "fieldset",
+  "figcaption",
+  "figure",
+  "footer",
+  "form",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "header",
+  "hgroup",
+  "hr",
+  "listing",
+  "main",
+  "menu",
+  "nav",
+  "ol",
+  "p",
+  "plaintext",
+  "pre",
+  "search",
+  "section",
+  "summary",
+  "table",
+  "ul",
+  "xmp",
+];
+
+// Tags that the spec calls special, which can be open while
+// parsing. An open `li` is not implicitly closed by one nested
+// inside of any of these
+const SPECIAL_ELEMENTS: [&str; 57] = [
+  "address",
+  "applet",
+  "article",
+  "aside",
+  "blockquote",
+  "body",
+  "button",
+  "caption",
+  "center",
+  "colgroup",
+  "dd",
+  "details",
+  "dir",
+  "div",
+  "dl",
+  "dt",
+  "fieldset",
+  "figcaption",
+  "figure",
+  "footer",
+  "form",
+  "frameset",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "head",
+  "header",
+  "hgroup",
+  "html",
+  "li",
+  "listing",
+  "main",
+  "marquee",
+  "menu",
+  "nav",
+  "object",
+  "ol",
+  "p",
+  "plaintext",
+  "pre",
+  "search",
+  "section",
+  "select",
+  "summary",
+  "table",
+  "tbody",
+  "td",
+  "template",
+  "tfoot",
+  "th",
+  "thead",
+  "tr",
+  "ul",
+  "xmp",
+];
+
+// Tags that stop the search for an open `<p>` to close
+const BUTTON_SCOPE: [&str; 10] = [
+  "applet", "button", "caption", "html", "marquee", "object", "table", "td",
+  "template", "th",
+];
+
+// Tags whose contents are rendered with whitespace intact, so
+// text inside of them is never collapsed. `textarea` is raw
+// text, so its contents are never collapsed either
+const PRESERVE_WHITESPACE: [&str; 2] = ["pre", "code"];
+
+fn preserves_whitespace(name: &str) -> bool {
+  PRESERVE_WHITESPACE
+    .iter()
+    .any(|tag| tag.eq_ignore_ascii_case(name))
+}
+
+/// Reduces a run of whitespace between tags to a single
+/// character, keeping a line break if there was one
+fn collapse_whitespace(text: &str) -> &'static str {
+  if text.contains('\n') {
+    "\n"
+  } else {
+    " "
+  }
+}
+
+/// How strictly `parse_html_with_mode` treats missing end tags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+  /// Every tag that is not empty must be explicitly closed
+  #[default]
+  Strict,
+  /// End tags that HTML allows to be omitted, such as `</li>`
+  /// and `</p>`, are implied where the spec implies them
+  Tolerant,
+}
+
 #[derive(Clone, Debug)]
 pub enum ErrorKind {
   /// Encountered illegal sequence
   Illegal,
   /// Closing tag does not match previous opening tag
   UnbalancedTags,
-  /// Tried to parse `N > 65535` elements
+  /// Tried to parse more than `LEXEME_MEMORY_LIMIT` elements
   MemoryLimit,
 }
 
@@ -102,7 +450,8 @@ impl From<Error> for crate::trace::Error {
         ErrorKind::MemoryLimit => "Ran out of memory",
       }
       .to_string(),
-      backtrace: vec![format!("at line {} column {}", value.row, value.column)],
+      span: Some(value.span),
+      backtrace: vec![],
     }
   }
 }
@@ -110,23 +459,33 @@ impl From<Error> for crate::trace::Error {
 #[derive(Clone, Debug)]
 pub struct Error {
   pub kind: ErrorKind,
-  pub char_index: usize,
-  pub row: usize,
-  pub column: usize,
+  /// The lexeme being parsed when the error was found
+  pub span: Span,
 }
 
+/// A single piece of parsed HTML. Text is borrowed from the
+/// source where possible, and only owned when it had to be
+/// decoded or was created during compilation
 #[derive(Clone, Debug)]
-pub enum HtmlElement {
-  /// The required `<!DOCTYPE HTML>` preamble
-  DocType,
-  /// Text inside of a `<!-- comment -->`
-  Comment(String),
+pub enum HtmlElement<'a> {
+  /// A `<!DOCTYPE ...>` preamble, holding everything after the
+  /// `DOCTYPE` keyword, such as `html`
+  DocType(Cow<'a, str>),
+  /// Text inside of a `<![CDATA[ section ]]>`, as found in
+  /// inline SVG and MathML
+  CData(Cow<'a, str>),
+  /// A `<?target ...?>` processing instruction, holding
+  /// everything between the `<?` and `?>`
+  ProcessingInstruction(Cow<'a, str>),
+  /// Text inside of a `<!-- comment -->`, or of markup such as
+  /// `<!foo>` that browsers treat as one
+  Comment(Cow<'a, str>),
   /// Any opening tag, including <empty/> tags
   OpenTag {
     /// The name of the tag
-    name: String,
+    name: Cow<'a, str>,
     /// Attribute names and their values if present
-    attributes: Attributes,
+    attributes: Attributes<'a>,
     /// Whether the tag closes itself. Tags that are
     /// implicitly empty are:
     /// `area, base, br, col, command, embed, hr, img, input
@@ -135,60 +494,207 @@ pub enum HtmlElement {
   },
   /// Any closing tag that is not empty. Closing implicitly
   /// empty tags is an error
-  CloseTag { name: String },
-  /// Any inner text that is not entirely whitespace
-  Text(String),
+  CloseTag { name: Cow<'a, str> },
+  /// Any inner text. Text made entirely of whitespace is
+  /// collapsed to a single character unless it is inside of a
+  /// `pre` or `code` tag
+  Text(Cow<'a, str>),
   /// A `<script>` tag and its contents
   Script {
     /// Attribute names and their values if present
-    attributes: Attributes,
+    attributes: Attributes<'a>,
     /// The raw text between the open and close `<script>`
     /// tags
-    contents: String,
+    contents: Cow<'a, str>,
   },
   /// A `<style>` tag and its contents
   Style {
     /// Attribute names and their values if present
-    attributes: Attributes,
+    attributes: Attributes<'a>,
     /// The raw text between the open and close `<style>`
     /// tags
-    contents: String,
+    contents: Cow<'a, str>,
+  },
+  /// An element whose contents are not parsed, such as
+  /// `<textarea>`, `<title>` or `<noscript>`. Character
+  /// references in `textarea` and `title` are kept as written
+  RawText {
+    /// The name of the tag
+    name: Cow<'a, str>,
+    /// Attribute names and their values if present
+    attributes: Attributes<'a>,
+    /// The raw text between the open and close tags
+    contents: Cow<'a, str>,
   },
   Directive {
-    name: String,
-    attributes: Attributes,
-    contents: String,
+    name: Cow<'a, str>,
+    attributes: Attributes<'a>,
+    contents: Cow<'a, str>,
   },
 }
-fn serialize_attributes(attr: &Attributes) -> String {
+
+pub fn serialize_attributes(attr: &Attributes<'_>) -> String {
   attr
     .iter()
     .map(|(k, v)| {
       if v.is_empty() {
         format!(" {k}")
       } else {
-        format!(" {k}=\"{v}\"")
+        format!(" {k}=\"{}\"", escape_attribute(literal_value(v)))
       }
     })
     .collect::<Vec<_>>()
     .join("")
 }
 
-impl HtmlElement {
+fn borrow(s: &str) -> Cow<'_, str> {
+  Cow::Borrowed(s)
+}
+
+/// Borrows every key and value of `attr`
+pub fn borrow_attributes<'b>(attr: &'b Attributes<'_>) -> Attributes<'b> {
+  attr.iter().map(|(k, v)| (borrow(k), borrow(v))).collect()
+}
+
+/// Takes ownership of every key and value of `attr`
+pub fn own_attributes(attr: Attributes<'_>) -> Attributes<'static> {
+  attr
+    .into_iter()
+    .map(|(k, v)| (Cow::Owned(k.into_owned()), Cow::Owned(v.into_owned())))
+    .collect()
+}
+
+impl HtmlElement<'_> {
+  /// A copy of this lexeme that borrows all of its text from
+  /// `self` instead of allocating
+  pub fn as_borrowed(&self) -> HtmlElement<'_> {
+    match self {
+      Self::DocType(doctype) => HtmlElement::DocType(borrow(doctype)),
+      Self::CData(contents) => HtmlElement::CData(borrow(contents)),
+      Self::ProcessingInstruction(contents) => {
+        HtmlElement::ProcessingInstruction(borrow(contents))
+      },
+      Self::Comment(comment) => HtmlElement::Comment(borrow(comment)),
+      Self::OpenTag {
+        name,
+        attributes,
+        is_empty,
+      } => HtmlElement::OpenTag {
+        name: borrow(name),
+        attributes: borrow_attributes(attributes),
+        is_empty: *is_empty,
+      },
+      Self::CloseTag { name } => HtmlElement::CloseTag { name: borrow(name) },
+      Self::Text(text) => HtmlElement::Text(borrow(text)),
+      Self::Script {
+        attributes,
+        contents,
+      } => HtmlElement::Script {
+        attributes: borrow_attributes(attributes),
+        contents: borrow(contents),
+      },
+      Self::Style {
+        attributes,
+        contents,
+      } => HtmlElement::Style {
+        attributes: borrow_attributes(attributes),
+        contents: borrow(contents),
+      },
+      Self::RawText {
+        name,
+        attributes,
+        contents,
+      } => HtmlElement::RawText {
+        name: borrow(name),
+        attributes: borrow_attributes(attributes),
+        contents: borrow(contents),
+      },
+      Self::Directive {
+        name,
+        attributes,
+        contents,
+      } => HtmlElement::Directive {
+        name: borrow(name),
+        attributes: borrow_attributes(attributes),
+        contents: borrow(contents),
+      },
+    }
+  }
+
+  /// Copies any borrowed text so the lexeme can outlive the
+  /// source it was parsed from
+  pub fn into_owned(self) -> HtmlElement<'static> {
+    let own = |s: Cow<'_, str>| -> Cow<'static, str> { Cow::Owned(s.into()) };
+    match self {
+      Self::DocType(doctype) => HtmlElement::DocType(own(doctype)),
+      Self::CData(contents) => HtmlElement::CData(own(contents)),
+      Self::ProcessingInstruction(contents) => {
+        HtmlElement::ProcessingInstruction(own(contents))
+      },
+      Self::Comment(comment) => HtmlElement::Comment(own(comment)),
+      Self::OpenTag {
+        name,
+        attributes,
+        is_empty,
+      } => HtmlElement::OpenTag {
+        name: own(name),
+        attributes: own_attributes(attributes),
+        is_empty,
+      },
+      Self::CloseTag { name } => HtmlElement::CloseTag { name: own(name) },
+      Self::Text(text) => HtmlElement::Text(own(text)),
+      Self::Script {
+        attributes,
+        contents,
+      } => HtmlElement::Script {
+        attributes: own_attributes(attributes),
+        contents: own(contents),
+      },
+      Self::Style {
+        attributes,
+        contents,
+      } => HtmlElement::Style {
+        attributes: own_attributes(attributes),
+        contents: own(contents),
+      },
+      Self::RawText {
+        name,
+        attributes,
+        contents,
+      } => HtmlElement::RawText {
+        name: own(name),
+        attributes: own_attributes(attributes),
+        contents: own(contents),
+      },
+      Self::Directive {
+        name,
+        attributes,
+        contents,
+      } => HtmlElement::Directive {
+        name: own(name),
+        attributes: own_attributes(attributes),
+        contents: own(contents),
+      },
+    }
+  }
+
   pub fn serialize(&self) -> String {
     match self {
-      Self::DocType => "<!DOCTYPE html>".into(),
-      Self::Comment(_) => format!("<!---->"),
+      Self::DocType(doctype) if doctype.is_empty() => "<!DOCTYPE>".into(),
+      Self::DocType(doctype) => format!("<!DOCTYPE {}>", doctype),
+      Self::CData(contents) => format!("<![CDATA[{}]]>", contents),
+      Self::ProcessingInstruction(contents) => format!("<?{}?>", contents),
+      Self::Comment(_) => "<!---->".into(),
       Self::OpenTag {
         name,
         attributes,
         is_empty: false,
-      } => format!("<{}{}>", name, serialize_attributes(&attributes)),
+      } => format!("<{}{}>", name, serialize_attributes(attributes)),
       Self::OpenTag {
         name,
         attributes,
         is_empty: true,
-      } => format!("<{}{}/>", name, serialize_attributes(&attributes)),
+      } => format!("<{}{}/>", name, serialize_attributes(attributes)),
       Self::CloseTag { name } => format!("</{}>", name),
       Self::Style {
         attributes,
@@ -210,103 +716,145 @@ impl HtmlElement {
           contents
         )
       },
-      Self::Text(t) => t.clone(),
+      Self::RawText {
+        name,
+        attributes,
+        contents,
+      } => format!(
+        "<{}{}>{}</{}>",
+        name,
+        serialize_attributes(attributes),
+        contents,
+        name
+      ),
+      Self::Text(t) => t.to_string(),
       Self::Directive {
         name,
         attributes,
         contents,
-      } => expand_directive(&name, &attributes, &contents),
+      } => expand_directive(name, attributes, contents),
     }
   }
 }
 
 /// Used as the `condition` argument for `parse_until` to
 /// parse names of things
-const NAME_REGEX: fn(char) -> bool =
-  |c| !(c.is_ascii_alphanumeric() || [':', '_', '-', '@'].contains(&c));
+const NAME_REGEX: fn(u8) -> bool =
+  |c| !(c.is_ascii_alphanumeric() || b":_-@".contains(&c));
+
+/// Used as the `condition` argument for `parse_until` to
+/// parse attribute names, which may contain anything but
+/// whitespace, `/`, `>` and `=`. This allows framework
+/// attributes such as `@click`, `:class` and `[data]`
+const ATTRIBUTE_NAME_REGEX: fn(u8) -> bool =
+  |c| c.is_ascii_whitespace() || b"/>=".contains(&c);
+
+/// Used as the `condition` argument for `parse_until` to
+/// parse unquoted attribute values such as `width=50%`
+const UNQUOTED_VALUE_REGEX: fn(u8) -> bool =
+  |c| c.is_ascii_whitespace() || c == b'>';
 
 /// Used as the `condition` argument for `parse_until` to
 /// arbitrary whitespace
-const WS_REGEX: fn(char) -> bool = |c| !c.is_whitespace();
+const WS_REGEX: fn(u8) -> bool = |c| !c.is_ascii_whitespace();
 
-fn parse_doctype(i: &str) -> MaybeParse<HtmlElement> {
+/// Parses a doctype. As in browsers, `<!doctype>` without a
+/// name is still a doctype, just an empty one, and so is one
+/// missing the space before its name, as in `<!doctypehtml>`
+fn parse_doctype(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
   let (_, i, o1) = parse_str(i, "<!doctype")?;
   let (_, i, o2) = parse_until(i, WS_REGEX);
-  // Minimum of 1 whitespace
-  if o2 == 0 {
-    return None;
-  }
-  let (_, i, o3) = parse_str(i, "html")?;
-  let (_, i, o4) = parse_until(i, WS_REGEX);
-  let (_, i, o5) = parse_str(i, ">")?;
-  Some((HtmlElement::DocType, i, o1 + o2 + o3 + o4 + o5))
+  let (doctype, i, o3) = parse_until(i, |c| c == b'>');
+  let (_, i, o4) = parse_str(i, ">")?;
+  Some((
+    HtmlElement::DocType(doctype.trim_end().into()),
+    i,
+    o1 + o2 + o3 + o4,
+  ))
+}
+
+fn parse_cdata(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let (_, i, o1) = parse_str(i, "<![CDATA[")?;
+  let (contents, i, o2) = parse_until_str(i, "]]>")?;
+  let (_, i, o3) = parse_str(i, "]]>")?;
+  Some((HtmlElement::CData(contents.into()), i, o1 + o2 + o3))
+}
+
+fn parse_processing_instruction(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let (_, i, o1) = parse_str(i, "<?")?;
+  let (contents, i, o2) = parse_until_str(i, "?>")?;
+  let (_, i, o3) = parse_str(i, "?>")?;
+  Some((
+    HtmlElement::ProcessingInstruction(contents.into()),
+    i,
+    o1 + o2 + o3,
+  ))
+}
+
+/// Parses markup such as `<!foo>` that starts like a doctype
+/// or comment but is neither, which browsers treat as a comment
+/// running up to the next `>`
+fn parse_bogus_comment(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let (_, i, o1) = parse_str(i, "<!")?;
+  let (contents, i, o2) = parse_until(i, |c| c == b'>');
+  let (_, i, o3) = parse_str(i, ">")?;
+  Some((HtmlElement::Comment(contents.into()), i, o1 + o2 + o3))
 }
 
-fn parse_comment<'a>(tail: &'a str) -> MaybeParse<HtmlElement> {
+fn parse_comment(tail: &str) -> MaybeParse<'_, HtmlElement<'_>> {
   let (_, tail, o1) = parse_str(tail, "<!--")?;
   let (comment, tail, o2) = parse_until_str(tail, "-->")?;
   let (_, tail, o3) = parse_str(tail, "-->")?;
   Some((HtmlElement::Comment(comment.into()), tail, o1 + o2 + o3))
 }
 
-fn parse_raw_text(i: &str) -> MaybeParse<(String, Attributes, String)> {
-  let (open, mut i, o1) = parse_open_tag(i)?;
-  let (open_name, attributes, is_empty) = match open {
-    HtmlElement::OpenTag {
-      name,
-      attributes,
-      is_empty,
-    } => (name, attributes, is_empty),
-    _ => unreachable!(),
-  };
+/// Parses raw text up to the first `</name` that is followed by
+/// whitespace, `/` or `>`, ignoring case. Anything else,
+/// including other tags, is part of the text
+fn parse_until_close_tag<'a>(i: &'a str, name: &str) -> Parse<'a, &'a str> {
+  let mut start = 0;
+  while let Some(pos) = i[start..].find("</") {
+    let end = start + pos;
+    if let Some((_, tail, _)) = parse_str(&i[end + 2..], name) {
+      let terminated = tail
+        .bytes()
+        .next()
+        .is_none_or(|c| c == b'>' || c == b'/' || c.is_ascii_whitespace());
+      if terminated {
+        return (&i[..end], &i[end..], end);
+      }
+    }
+    start = end + 2;
+  }
+  (i, "", i.len())
+}
+
+fn parse_raw_text(i: &str) -> MaybeParse<'_, (&str, Attributes<'_>, &str)> {
+  let ((open_name, attributes, is_empty), i, o1) = parse_open_tag_parts(i)?;
 
   let (close_name, contents, i, o2) = if is_empty {
-    (open_name.clone(), "".to_string(), i, 0)
+    (open_name, "", i, 0)
   } else {
-    let mut contents = String::new();
-    let mut o2 = 0;
-    while !i.is_empty() {
-      let (text, new_i, new_off) = parse_text(i);
-      contents.push_str(&text);
-      o2 += new_off;
-      i = new_i;
-      if i.starts_with("</") {
-        if let Some((HtmlElement::CloseTag { name }, _, _)) = parse_close_tag(i)
-        {
-          if name == open_name {
-            break;
-          }
-        }
-      }
-      if text.is_empty() {
-        i = &i[1..];
-        o2 += 1;
-        contents.push('<');
-      }
-    }
-    let (close, i, o3) = parse_close_tag(i)?;
-    let close_name = match close {
-      HtmlElement::CloseTag { name } => name,
-      _ => unreachable!(),
-    };
+    let (contents, i, o2) = parse_until_close_tag(i, open_name);
+    let (close_name, i, o3) = parse_close_tag_name(i)?;
     (close_name, contents, i, o2 + o3)
   };
-  if open_name != close_name {
+  if !open_name.eq_ignore_ascii_case(close_name) {
     return None;
   }
 
   Some(((open_name, attributes, contents), i, o1 + o2))
 }
 
-fn parse_style(i: &str) -> MaybeParse<HtmlElement> {
+fn parse_style(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
   let ((name, attributes, contents), i, o) = parse_raw_text(i)?;
-  if name != "style" {
+  if !name.eq_ignore_ascii_case("style") {
     None
   } else {
     Some((
       HtmlElement::Style {
         attributes,
-        contents,
+        contents: contents.into(),
       },
       i,
       o,
@@ -314,15 +862,15 @@ fn parse_style(i: &str) -> MaybeParse<HtmlElement> {
   }
 }
 
-fn parse_script(i: &str) -> MaybeParse<HtmlElement> {
+fn parse_script(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
   let ((name, attributes, contents), i, o) = parse_raw_text(i)?;
-  if name != "script" {
+  if !name.eq_ignore_ascii_case("script") {
     None
   } else {
     Some((
       HtmlElement::Script {
         attributes,
-        contents,
+        contents: contents.into(),
       },
       i,
       o,
@@ -330,14 +878,62 @@ fn parse_script(i: &str) -> MaybeParse<HtmlElement> {
   }
 }
 
-fn parse_directive(i: &str) -> MaybeParse<HtmlElement> {
+fn parse_raw_text_element(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let ((name, attributes, contents), i, o) = parse_raw_text(i)?;
+  Some((
+    HtmlElement::RawText {
+      name: name.into(),
+      attributes,
+      contents: contents.into(),
+    },
+    i,
+    o,
+  ))
+}
+
+/// The name of the open tag `i` starts with, if any
+fn open_tag_name(i: &str) -> Option<&str> {
+  let (_, i, _) = parse_str(i, "<")?;
+  Some(parse_until(i, NAME_REGEX).0)
+}
+
+/// Whether `i` starts with an open tag named `name`, ignoring
+/// case. Longer names such as `<style-guide>` do not match
+fn starts_with_tag(i: &str, name: &str) -> bool {
+  open_tag_name(i).is_some_and(|open| open.eq_ignore_ascii_case(name))
+}
+
+/// Whether `i` starts with the open tag of an element whose
+/// contents are raw text, other than `style` and `script`
+fn starts_with_raw_text_tag(i: &str) -> bool {
+  let Some(name) = open_tag_name(i) else {
+    return false;
+  };
+  RAW_TEXT_ELEMENTS
+    .iter()
+    .chain(ESCAPABLE_RAW_TEXT_ELEMENTS.iter())
+    .any(|raw| raw.eq_ignore_ascii_case(name))
+}
+
+/// Whether `i` starts with a directive whose body is HTML. These
+/// are parsed like elements named `@name`, so that their
+/// contents can use templates
+fn starts_with_html_directive(i: &str) -> bool {
+  let Some((_, i, _)) = parse_str(i, "<@") else {
+    return false;
+  };
+  let (name, _, _) = parse_until(i, NAME_REGEX);
+  directive_body(name) == DirectiveBody::Html
+}
+
+fn parse_directive(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
   let ((name, attributes, contents), i, o) = parse_raw_text(i)?;
   if let Some(name) = name.strip_prefix('@') {
     Some((
       HtmlElement::Directive {
-        name: name.to_string(),
+        name: name.into(),
         attributes,
-        contents,
+        contents: contents.into(),
       },
       i,
       o,
@@ -347,60 +943,93 @@ fn parse_directive(i: &str) -> MaybeParse<HtmlElement> {
   }
 }
 
-fn parse_open_tag(i: &str) -> MaybeParse<HtmlElement> {
-  let (_, i, o1) = parse_str(i, "<")?;
-  let (name, i, o2) = parse_until(i, NAME_REGEX);
-  let mut attributes = HashMap::new();
+/// Parses the attributes of an open tag in the order they are
+/// written, and whether the tag ends in `/>`
+fn parse_attribute_list(i: &str) -> Parse<'_, (Vec<Attribute<'_>>, bool)> {
+  let mut attributes = vec![];
   let mut i = i;
-  let mut o3 = 0;
-  while let Some(((key, value), new_i, new_o)) = parse_attribute(i) {
-    attributes.insert(key, value);
-    i = new_i;
-    o3 += new_o;
+  let mut offset = 0;
+  let mut is_empty = false;
+  loop {
+    let (_, tail, o) = parse_until(i, WS_REGEX);
+    offset += o;
+    i = tail;
+    // As in browsers, a `/` anywhere but just before the `>` is
+    // ignored, so `<a x/y>` has the attributes `x` and `y`
+    if let Some((_, tail, o)) = parse_char(i, b'/') {
+      offset += o;
+      i = tail;
+      if i.starts_with('>') {
+        is_empty = true;
+        break;
+      }
+      continue;
+    }
+    // Attributes need no whitespace between them after a quoted
+    // value, as in `title="a"b`
+    let Some((attribute, tail, o)) = parse_attribute(i) else {
+      break;
+    };
+    attributes.push(attribute);
+    offset += o;
+    i = tail;
   }
-  let (_, i, o4) = parse_until(i, WS_REGEX);
-  // Find all attributes
-  let (is_empty, i, o5) = match parse_str(i, "/") {
-    Some((_, i, o5)) => (true, i, o5),
-    None => (false, i, 0),
-  };
+  ((attributes, is_empty), i, offset)
+}
+
+/// The attributes of the open tag at the start of `tag` in the
+/// order they are written, which `Attributes` does not keep
+pub fn ordered_attributes(tag: &str) -> Option<Vec<Attribute<'_>>> {
+  let (_, i, _) = parse_str(tag, "<")?;
+  let (_, i, _) = parse_until(i, NAME_REGEX);
+  let ((attributes, _), _, _) = parse_attribute_list(i);
+  Some(attributes)
+}
 
+fn parse_open_tag_parts(
+  i: &str,
+) -> MaybeParse<'_, (&str, Attributes<'_>, bool)> {
+  let (_, i, o1) = parse_str(i, "<")?;
+  let (name, i, o2) = parse_until(i, NAME_REGEX);
+  let ((attributes, is_empty), i, o3) = parse_attribute_list(i);
+  let attributes = attributes.into_iter().collect();
   let is_empty = is_empty || VOID_ELEMENTS.contains(&name);
-  let (_, i, o6) = parse_char(i, '>')?;
+  let (_, i, o4) = parse_char(i, b'>')?;
+  Some(((name, attributes, is_empty), i, o1 + o2 + o3 + o4))
+}
+
+fn parse_open_tag(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let ((name, attributes, is_empty), i, o) = parse_open_tag_parts(i)?;
   Some((
     HtmlElement::OpenTag {
-      name: name.to_string(),
+      name: name.into(),
       attributes,
       is_empty,
     },
     i,
-    o1 + o2 + o3 + o4 + o5 + o6,
+    o,
   ))
 }
 
-fn parse_attribute(i: &str) -> MaybeParse<(String, String)> {
-  let (_, i, o1) = parse_until(i, WS_REGEX);
+fn parse_attribute(i: &str) -> MaybeParse<'_, Attribute<'_>> {
+  let (key, i, o1) = parse_until(i, ATTRIBUTE_NAME_REGEX);
   if o1 == 0 {
     return None;
   }
-  let (key, i, o2) = parse_until(i, NAME_REGEX);
-  if o2 == 0 {
-    return None;
-  }
   let get_value = || -> Option<(&str, &str, Offset)> {
     let (_, i, o1) = parse_until(i, WS_REGEX);
     let (_, i, o2) = parse_str(i, "=")?;
     let (_, i, o3) = parse_until(i, WS_REGEX);
-    let (value, i, o4) = parse_delimited(i, '\"')
-      .or_else(|| parse_delimited(i, '\''))
-      .unwrap_or_else(|| parse_until(i, NAME_REGEX));
+    let (value, i, o4) = parse_delimited(i, b'"')
+      .or_else(|| parse_delimited(i, b'\''))
+      .unwrap_or_else(|| parse_until(i, UNQUOTED_VALUE_REGEX));
     Some((value, i, o1 + o2 + o3 + o4))
   };
-  let (value, i, o3) = get_value().unwrap_or(("", i, 0));
-  Some(((key.to_string(), value.to_string()), i, o1 + o2 + o3))
+  let (value, i, o2) = get_value().unwrap_or(("", i, 0));
+  Some(((key.into(), decode_attribute_value(value)), i, o1 + o2))
 }
 
-fn parse_close_tag(i: &str) -> MaybeParse<HtmlElement> {
+fn parse_close_tag_name(i: &str) -> MaybeParse<'_, &str> {
   let (_, i, o1) = parse_str(i, "</")?;
   let (name, i, o2) = parse_until(i, NAME_REGEX);
   if o2 == 0 {
@@ -408,66 +1037,246 @@ fn parse_close_tag(i: &str) -> MaybeParse<HtmlElement> {
   }
   let (_, i, o3) = parse_until(i, WS_REGEX);
   let (_, i, o4) = parse_str(i, ">")?;
-  Some((
-    HtmlElement::CloseTag {
-      name: name.to_string(),
-    },
-    i,
-    o1 + o2 + o3 + o4,
-  ))
+  Some((name, i, o1 + o2 + o3 + o4))
+}
+
+fn parse_close_tag(i: &str) -> MaybeParse<'_, HtmlElement<'_>> {
+  let (name, i, o) = parse_close_tag_name(i)?;
+  Some((HtmlElement::CloseTag { name: name.into() }, i, o))
+}
+
+fn parse_text(i: &str) -> Parse<'_, &str> {
+  parse_until(i, |c| c == b'<')
+}
+
+/// Whether opening a `name` tag implies the end of an open
+/// `current` tag when it is the innermost open tag
+fn closed_by_open_tag(current: &str, name: &str) -> bool {
+  match current.to_ascii_lowercase().as_str() {
+    "head" => name == "body",
+    "rt" | "rp" => matches!(name, "rt" | "rp"),
+    "optgroup" => name == "optgroup",
+    "option" => matches!(name, "option" | "optgroup"),
+    _ => false,
+  }
+}
+
+/// Where the search for a tag implicitly closed by an open tag
+/// stops
+#[derive(Clone, Copy)]
+enum SearchScope {
+  /// Stops at elements that the spec calls special, other than
+  /// `address`, `div` and `p`, as when looking for an open `li`
+  ListItem,
+  /// Stops at the tags that the spec's button scope stops at,
+  /// as when looking for an open `p`
+  Button,
+  /// Stops at the table, as when looking for an open `td`
+  Table,
+}
+
+impl SearchScope {
+  fn stops_at(self, name: &str) -> bool {
+    let stops: &[&str] = match self {
+      SearchScope::ListItem => &SPECIAL_ELEMENTS,
+      SearchScope::Button => &BUTTON_SCOPE,
+      SearchScope::Table => &["html", "table", "template"],
+    };
+    let name = name.to_ascii_lowercase();
+    let name = name.as_str();
+    match self {
+      SearchScope::ListItem if matches!(name, "address" | "div" | "p") => false,
+      _ => stops.contains(&name),
+    }
+  }
+}
+
+/// The position of the innermost tag in `stack` that is one of
+/// `targets` and within `scope`
+fn find_in_scope(
+  stack: &[Cow<'_, str>],
+  targets: &[&str],
+  scope: SearchScope,
+) -> Option<usize> {
+  for (index, open) in stack.iter().enumerate().rev() {
+    if targets
+      .iter()
+      .any(|target| target.eq_ignore_ascii_case(open))
+    {
+      return Some(index);
+    }
+    if scope.stops_at(open) {
+      return None;
+    }
+  }
+  None
+}
+
+/// The open tags of `stack` that the tags opened by `name`
+/// searches for and implicitly closes, along with everything
+/// opened inside of them
+fn scoped_targets(
+  name: &str,
+) -> Option<(&'static [&'static str], SearchScope)> {
+  match name {
+    "li" => Some((&["li"], SearchScope::ListItem)),
+    "dd" | "dt" => Some((&["dd", "dt"], SearchScope::ListItem)),
+    "td" | "th" => Some((&["td", "th"], SearchScope::Table)),
+    "tr" => Some((&["tr", "caption", "colgroup"], SearchScope::Table)),
+    "tbody" | "thead" | "tfoot" => Some((
+      &["tbody", "thead", "tfoot", "caption", "colgroup"],
+      SearchScope::Table,
+    )),
+    "caption" | "colgroup" => Some((
+      &["caption", "colgroup", "tbody", "thead", "tfoot"],
+      SearchScope::Table,
+    )),
+    _ => None,
+  }
 }
 
-fn parse_text(i: &str) -> Parse<String> {
-  let (text, i, o1) = parse_until(i, |c| c == '<');
-  (text.to_string(), i, o1)
+/// How many of the tags on `stack` stay open when a `name` tag
+/// is opened, following the spec's rules for the tags whose end
+/// tags may be left out
+fn open_after_open_tag(stack: &[Cow<'_, str>], name: &str) -> usize {
+  let name = name.to_ascii_lowercase();
+  let name = name.as_str();
+  let mut open = stack.len();
+  while open > 0 && closed_by_open_tag(&stack[open - 1], name) {
+    open -= 1;
+  }
+  if let Some((targets, scope)) = scoped_targets(name) {
+    if let Some(index) = find_in_scope(&stack[..open], targets, scope) {
+      open = index;
+    }
+  }
+  if matches!(name, "li" | "dd" | "dt") || CLOSES_PARAGRAPH.contains(&name) {
+    if let Some(index) =
+      find_in_scope(&stack[..open], &["p"], SearchScope::Button)
+    {
+      open = index;
+    }
+  }
+  open
+}
+
+fn has_optional_end_tag(name: &str) -> bool {
+  OPTIONAL_END_TAGS
+    .iter()
+    .any(|tag| tag.eq_ignore_ascii_case(name))
+}
+
+/// Pushes the close tags implied by `lm` onto `output`,
+/// popping them off of the open tag `stack`
+fn close_implied_tags<'a>(
+  lm: &HtmlElement<'a>,
+  span: &Span,
+  stack: &mut Vec<Cow<'a, str>>,
+  output: &mut Vec<(HtmlElement<'a>, Span)>,
+) {
+  let mut implied = vec![];
+  match lm {
+    HtmlElement::OpenTag { name, .. } => {
+      let open = open_after_open_tag(stack, name);
+      implied.extend(stack.drain(open..).rev());
+    },
+    HtmlElement::CloseTag { name }
+      if stack.iter().any(|open| open.eq_ignore_ascii_case(name)) =>
+    {
+      while let Some(top) = stack.last() {
+        if top.eq_ignore_ascii_case(name) || !has_optional_end_tag(top) {
+          break;
+        }
+        implied.extend(stack.pop());
+      }
+    },
+    _ => {},
+  }
+  // Implied close tags take up no space in the source, so sit
+  // just before the lexeme that implied them
+  let implied_span = Span {
+    end: span.start,
+    ..span.clone()
+  };
+  output.extend(
+    implied
+      .into_iter()
+      .map(|name| (HtmlElement::CloseTag { name }, implied_span.clone())),
+  );
 }
 
 pub fn parse_html(
   input: &str,
-) -> Result<Vec<HtmlElement>, crate::trace::Error> {
+) -> Result<Vec<(HtmlElement<'_>, Span)>, crate::trace::Error> {
+  parse_html_with_mode(input, ParseMode::Strict)
+}
+
+pub fn parse_html_with_mode(
+  input: &str,
+  mode: ParseMode,
+) -> Result<Vec<(HtmlElement<'_>, Span)>, crate::trace::Error> {
+  parse_html_from(input, mode, None)
+}
+
+/// Parses `input` into lexemes and the spans they were found
+/// at, naming `file` as their source
+pub fn parse_html_from(
+  input: &str,
+  mode: ParseMode,
+  file: Option<Rc<Path>>,
+) -> Result<Vec<(HtmlElement<'_>, Span)>, crate::trace::Error> {
   let mut output = vec![];
   let mut validation_stack = vec![];
+  // Number of open tags whose whitespace must be kept. These
+  // never have optional end tags, so are only closed explicitly
+  let mut preserve_depth: usize = 0;
   let mut i = input;
-  let mut offset = 0;
-  let throw_err = |kind, offset| {
-    let (row, column) = index_to_rc(input, offset);
-    Error {
-      kind,
-      char_index: offset,
-      row,
-      column,
-    }
-    .into()
-  };
-  while i.len() > 0 {
+  let mut position = Position::new();
+  let throw_err = |kind, span| Error { kind, span }.into();
+  while !i.is_empty() {
     let (lm, new_i, new_off) = if i.starts_with("<!--") {
       parse_comment(i)
+    } else if i.starts_with("<![CDATA[") {
+      parse_cdata(i)
+    } else if i.starts_with("<?") {
+      parse_processing_instruction(i)
     } else if i.starts_with("<!") {
-      parse_doctype(i)
-    } else if i.starts_with("<style") {
+      parse_doctype(i).or_else(|| parse_bogus_comment(i))
+    } else if starts_with_tag(i, "style") {
       parse_style(i)
-    } else if i.starts_with("<script") {
+    } else if starts_with_tag(i, "script") {
       parse_script(i)
+    } else if starts_with_raw_text_tag(i) {
+      parse_raw_text_element(i)
     } else if i.starts_with("</") {
       parse_close_tag(i)
+    } else if starts_with_html_directive(i) {
+      parse_open_tag(i)
     } else if i.starts_with("<@") {
       parse_directive(i)
     } else if i.starts_with("<") {
       parse_open_tag(i)
     } else {
       let (text, new_i, new_off) = parse_text(i);
-      // skip if text is all whitespace
-      if text.trim().is_empty() {
-        i = new_i;
-        offset += new_off;
-        continue;
+      if text.trim().is_empty() && preserve_depth == 0 {
+        Some((
+          HtmlElement::Text(collapse_whitespace(text).into()),
+          new_i,
+          new_off,
+        ))
+      } else {
+        Some((HtmlElement::Text(text.into()), new_i, new_off))
       }
-      Some((HtmlElement::Text(text), new_i, new_off))
     }
-    .ok_or_else(|| throw_err(ErrorKind::Illegal, offset))?;
+    .ok_or_else(|| throw_err(ErrorKind::Illegal, position.span(&file, 0)))?;
+    let span = position.span(&file, new_off);
+
+    if mode == ParseMode::Tolerant {
+      close_implied_tags(&lm, &span, &mut validation_stack, &mut output);
+    }
 
     if output.len() >= LEXEME_MEMORY_LIMIT {
-      return Err(throw_err(ErrorKind::MemoryLimit, offset));
+      return Err(throw_err(ErrorKind::MemoryLimit, span));
     }
 
     match &lm {
@@ -475,23 +1284,470 @@ pub fn parse_html(
         name,
         is_empty: false,
         ..
-      } => validation_stack.push(name.clone()),
+      } => {
+        if preserves_whitespace(name) {
+          preserve_depth += 1;
+        }
+        validation_stack.push(name.clone());
+      },
       HtmlElement::CloseTag { name } => {
         let top = validation_stack.pop();
-        if top.map_or(true, |top| &top != name) {
-          return Err(throw_err(ErrorKind::UnbalancedTags, offset));
+        if !top.is_some_and(|top| top.eq_ignore_ascii_case(name)) {
+          return Err(throw_err(ErrorKind::UnbalancedTags, span));
+        }
+        if preserves_whitespace(name) {
+          preserve_depth -= 1;
         }
       },
       _ => {},
     };
 
-    output.push(lm);
+    output.push((lm, span));
     i = new_i;
-    offset += new_off;
+    position.advance(input, position.offset + new_off);
+  }
+  let end = position.span(&file, 0);
+  if mode == ParseMode::Tolerant {
+    while let Some(top) = validation_stack.last() {
+      if !has_optional_end_tag(top) {
+        break;
+      }
+      let name = validation_stack.pop().unwrap();
+      output.push((HtmlElement::CloseTag { name }, end.clone()));
+    }
   }
   if validation_stack.is_empty() {
     Ok(output)
   } else {
-    Err(throw_err(ErrorKind::UnbalancedTags, offset))
+    Err(throw_err(ErrorKind::UnbalancedTags, end))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The decoded `title` of the single tag in `source`
+  fn title(source: &str) -> String {
+    let lexemes = parse_html(source).unwrap();
+    let HtmlElement::OpenTag { attributes, .. } = &lexemes[0].0 else {
+      panic!("expected an open tag, found {:?}", lexemes[0].0);
+    };
+    attributes["title"].to_string()
+  }
+
+  fn round_trip(value: &str) {
+    let escaped = escape_attribute(value);
+    assert_eq!(decode_entities(&escaped), value, "escaped as {}", escaped);
+    let source = format!("<img title=\"{}\">", escaped);
+    assert_eq!(title(&source), value);
+  }
+
+  #[test]
+  fn escapes_quotes() {
+    assert_eq!(escape_attribute(r#"say "hi""#), "say &quot;hi&quot;");
+    assert_eq!(escape_attribute("it's"), "it's");
+    assert_eq!(title(r#"<img title="&quot;hi&quot;">"#), "\"hi\"");
+    assert_eq!(title(r#"<img title="it&#39;s &apos;">"#), "it's '");
+    assert_eq!(title("<img title='say \"hi\"'>"), "say \"hi\"");
+  }
+
+  #[test]
+  fn escapes_ampersands() {
+    assert_eq!(escape_attribute("a & b"), "a &amp; b");
+    assert_eq!(title(r#"<img title="a &amp; b">"#), "a & b");
+    assert_eq!(title(r#"<img title="a & b">"#), "a & b");
+    assert_eq!(title(r#"<img title="&amp;amp;">"#), "&amp;");
+  }
+
+  #[test]
+  fn decodes_named_references() {
+    assert_eq!(
+      title(r#"<img title="&eacute;&hellip;&mdash;&rarr;">"#),
+      "é…—→"
+    );
+    assert_eq!(title(r#"<img title="&NotEqualTilde;">"#), "\u{2242}\u{338}");
+    assert_eq!(title(r#"<img title="&unknown; &">"#), "&unknown; &");
+  }
+
+  #[test]
+  fn decodes_legacy_references() {
+    assert_eq!(title(r#"<img title="&amp &lt">"#), "& <");
+    assert_eq!(title(r#"<img title="&copy 2024">"#), "© 2024");
+    // Kept as written when they run into more of a name
+    assert_eq!(title(r#"<img title="?a=1&copy=2&ltx">"#), "?a=1&copy=2&ltx");
+  }
+
+  #[test]
+  fn decodes_numeric_references() {
+    assert_eq!(
+      title(r#"<img title="&#x41;&#64;&#X42;&#x1F600;">"#),
+      "A@B😀"
+    );
+    assert_eq!(title(r#"<img title="&#65 &#x42">"#), "A B");
+    assert_eq!(
+      title(r#"<img title="&#0;&#xD800;&#x110000;">"#),
+      "\u{fffd}".repeat(3)
+    );
+    assert_eq!(title(r#"<img title="&#; &#x;">"#), "&#; &#x;");
+  }
+
+  #[test]
+  fn keeps_unicode() {
+    assert_eq!(title(r#"<img title="日本語 ü ß 😀">"#), "日本語 ü ß 😀");
+    let lexemes = parse_html("héllo <b>wörld</b>").unwrap();
+    assert!(matches!(&lexemes[0].0, HtmlElement::Text(t) if t == "héllo "));
+    assert!(matches!(&lexemes[2].0, HtmlElement::Text(t) if t == "wörld"));
+  }
+
+  /// The lexemes of a strict parse of `source`, serialized
+  fn strict(source: &str) -> String {
+    parse_html(source)
+      .unwrap()
+      .iter()
+      .map(|(lm, _)| lm.serialize())
+      .collect()
+  }
+
+  #[test]
+  fn preserves_whitespace_between_inline_tags() {
+    assert_eq!(strict("<b>a</b> <i>b</i>"), "<b>a</b> <i>b</i>");
+    assert_eq!(strict("<b>a</b>\n\n   <i>b</i>"), "<b>a</b>\n<i>b</i>");
+    assert_eq!(
+      strict("<div>\n  <p>a</p>\n</div>"),
+      "<div>\n<p>a</p>\n</div>"
+    );
+  }
+
+  #[test]
+  fn preserves_whitespace_inside_pre() {
+    let source = "<pre>\n  fn main() {\n    <code>  x</code>\n\n  }\n</pre>";
+    assert_eq!(strict(source), source);
+    assert_eq!(
+      strict("<PRE>\n\n  </pre><div>\n\n  </div>"),
+      "<PRE>\n\n  </pre><div>\n</div>"
+    );
+    assert_eq!(
+      strict("<pre>x</PRE><div>\n\n  </div>"),
+      "<pre>x</PRE><div>\n</div>"
+    );
+  }
+
+  #[test]
+  fn round_trips_cdata_and_processing_instructions() {
+    let source = "<svg><![CDATA[a < b && <c>]]></svg>";
+    assert_eq!(strict(source), source);
+    let lexemes = parse_html(source).unwrap();
+    assert!(matches!(
+      &lexemes[1].0,
+      HtmlElement::CData(contents) if contents == "a < b && <c>"
+    ));
+    let source = r#"<?xml version="1.0" encoding="UTF-8"?><svg/>"#;
+    assert_eq!(strict(source), source);
+  }
+
+  #[test]
+  fn round_trips_doctypes() {
+    assert_eq!(strict("<!doctype html>"), "<!DOCTYPE html>");
+    let legacy =
+      "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\"\n  \
+                  \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">";
+    assert_eq!(strict(legacy), legacy);
+    assert_eq!(strict("<!doctype>"), "<!DOCTYPE>");
+    assert_eq!(strict("<!doctypehtml>"), "<!DOCTYPE html>");
+  }
+
+  #[test]
+  fn parses_bogus_comments() {
+    let lexemes = parse_html("<!foo bar><!-x-><p></p>").unwrap();
+    assert!(matches!(
+      &lexemes[0].0,
+      HtmlElement::Comment(contents) if contents == "foo bar"
+    ));
+    assert!(matches!(
+      &lexemes[1].0,
+      HtmlElement::Comment(contents) if contents == "-x-"
+    ));
+    assert!(parse_html("<!foo").is_err());
+  }
+
+  #[test]
+  fn parses_custom_elements_named_like_raw_text() {
+    let lexemes =
+      parse_html("<style-guide>x</style-guide><scripted/>").unwrap();
+    assert!(matches!(
+      &lexemes[0].0,
+      HtmlElement::OpenTag { name, .. } if name == "style-guide"
+    ));
+    assert!(matches!(&lexemes[1].0, HtmlElement::Text(t) if t == "x"));
+    assert!(matches!(
+      &lexemes[3].0,
+      HtmlElement::OpenTag { name, is_empty: true, .. } if name == "scripted"
+    ));
+    let lexemes = parse_html("<STYLE>a < b</STYLE>").unwrap();
+    assert!(matches!(
+      &lexemes[0].0,
+      HtmlElement::Style { contents, .. } if contents == "a < b"
+    ));
+  }
+
+  /// The lexemes of a tolerant parse of `source`, serialized
+  fn tolerant(source: &str) -> String {
+    parse_html_with_mode(source, ParseMode::Tolerant)
+      .unwrap()
+      .iter()
+      .map(|(lm, _)| lm.serialize())
+      .collect()
+  }
+
+  #[test]
+  fn implies_paragraph_end_tags() {
+    assert_eq!(
+      tolerant("<p>intro<ul><li>a</ul>"),
+      "<p>intro</p><ul><li>a</li></ul>"
+    );
+    assert_eq!(
+      tolerant("<p>a<table><tr><td>b</table>"),
+      "<p>a</p><table><tr><td>b</td></tr></table>"
+    );
+    assert_eq!(
+      tolerant("<p>a<search>b</search>"),
+      "<p>a</p><search>b</search>"
+    );
+    assert_eq!(tolerant("<p>a<span>b</span>"), "<p>a<span>b</span></p>");
+  }
+
+  #[test]
+  fn implies_end_tags_below_an_open_paragraph() {
+    assert_eq!(
+      tolerant("<ul><li><p>one<li><p>two</ul>"),
+      "<ul><li><p>one</p></li><li><p>two</p></li></ul>"
+    );
+    assert_eq!(
+      tolerant("<dl><dt><p>a<dd><p>b</dl>"),
+      "<dl><dt><p>a</p></dt><dd><p>b</p></dd></dl>"
+    );
+    assert_eq!(
+      tolerant("<table><tr><td><p>a<td>b</table>"),
+      "<table><tr><td><p>a</p></td><td>b</td></tr></table>"
+    );
+    assert_eq!(
+      tolerant("<table><tr><td>a<tr><td>b</table>"),
+      "<table><tr><td>a</td></tr><tr><td>b</td></tr></table>"
+    );
+    assert_eq!(
+      tolerant("<p><span>a<div>b</div>"),
+      "<p><span>a</span></p><div>b</div>"
+    );
+  }
+
+  #[test]
+  fn stops_searching_for_implied_end_tags_at_scope_boundaries() {
+    // A list nested in an item is special, so the inner item
+    // does not close the outer one
+    assert_eq!(
+      tolerant("<ul><li>a<ul><li>b</ul></ul>"),
+      "<ul><li>a<ul><li>b</li></ul></li></ul>"
+    );
+    assert_eq!(
+      tolerant("<li>a<section><li>b</section>"),
+      "<li>a<section><li>b</li></section></li>"
+    );
+    // Unlike other special tags, `div` does not stop the search
+    assert_eq!(
+      tolerant("<li>a<div><li>b"),
+      "<li>a<div></div></li><li>b</li>"
+    );
+    // A paragraph outside of a button or table cell stays open
+    assert_eq!(
+      tolerant("<p>a<button><div>b</div></button>"),
+      "<p>a<button><div>b</div></button></p>"
+    );
+    assert_eq!(
+      tolerant("<td><table><tr><td>a</table><td>b"),
+      "<td><table><tr><td>a</td></tr></table></td><td>b</td>"
+    );
+  }
+
+  #[test]
+  fn implies_end_tags_ignoring_case() {
+    assert_eq!(tolerant("<P>a<DIV>b</div>"), "<P>a</P><DIV>b</div>");
+    assert_eq!(
+      tolerant("<UL><LI>a<li>b</ul>"),
+      "<UL><LI>a</LI><li>b</li></ul>"
+    );
+  }
+
+  #[test]
+  fn keeps_decoded_at_signs_literal() {
+    assert_eq!(title(r#"<img title="&#64;t">"#), "@@t");
+    assert_eq!(title(r#"<img title="&commat;t">"#), "@@t");
+    assert_eq!(title(r#"<img title="@t">"#), "@t");
+    let serialized: String = parse_html(r#"<img title="&#64;t"><br a="@@b">"#)
+      .unwrap()
+      .iter()
+      .map(|(lm, _)| lm.serialize())
+      .collect();
+    assert_eq!(serialized, r#"<img title="@t"/><br a="@b"/>"#);
+  }
+
+  /// The attributes of the single tag in `source`, sorted
+  fn attributes(source: &str) -> Vec<(String, String)> {
+    let lexemes = parse_html(source).unwrap();
+    let HtmlElement::OpenTag { attributes, .. } = &lexemes[0].0 else {
+      panic!("expected an open tag, found {:?}", lexemes[0].0);
+    };
+    let mut attributes: Vec<_> = attributes
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect();
+    attributes.sort();
+    attributes
+  }
+
+  fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+    expected
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn parses_unquoted_values() {
+    assert_eq!(
+      attributes("<img href=/home width=50% x=a=b>"),
+      pairs(&[("href", "/home"), ("width", "50%"), ("x", "a=b")])
+    );
+    assert_eq!(attributes("<img src=a.png/>"), pairs(&[("src", "a.png/")]));
+  }
+
+  #[test]
+  fn parses_framework_attribute_names() {
+    assert_eq!(
+      attributes(
+        r#"<input @click="go" :class="c" x-on:submit.prevent="s" [data]="d" (input)="i">"#
+      ),
+      pairs(&[
+        ("(input)", "i"),
+        (":class", "c"),
+        ("@click", "go"),
+        ("[data]", "d"),
+        ("x-on:submit.prevent", "s"),
+      ])
+    );
+  }
+
+  #[test]
+  fn ignores_stray_slashes() {
+    assert_eq!(attributes("<img x/y>"), pairs(&[("x", ""), ("y", "")]));
+    assert_eq!(
+      attributes(r#"<img / title="t" / >"#),
+      pairs(&[("title", "t")])
+    );
+    let lexemes = parse_html("<a x />").unwrap();
+    assert!(matches!(
+      &lexemes[0].0,
+      HtmlElement::OpenTag { is_empty: true, .. }
+    ));
+  }
+
+  #[test]
+  fn parses_attributes_after_quoted_values() {
+    assert_eq!(
+      attributes(r#"<img title="a"b c='d'e=f>"#),
+      pairs(&[("b", ""), ("c", "d"), ("e", "f"), ("title", "a")])
+    );
+  }
+
+  #[test]
+  fn borrows_text_that_needs_no_decoding() {
+    let source = r#"<img title="plain" alt="a &amp; b" src=x>text<!--c--><script>s</script>"#;
+    let lexemes = parse_html(source).unwrap();
+    let HtmlElement::OpenTag {
+      name, attributes, ..
+    } = &lexemes[0].0
+    else {
+      panic!("expected an open tag, found {:?}", lexemes[0].0);
+    };
+    assert!(matches!(name, Cow::Borrowed("img")));
+    assert!(matches!(attributes["title"], Cow::Borrowed("plain")));
+    assert!(matches!(attributes["src"], Cow::Borrowed("x")));
+    assert!(matches!(&attributes["alt"], Cow::Owned(alt) if alt == "a & b"));
+    assert!(matches!(attributes.keys().next(), Some(Cow::Borrowed(_))));
+    assert!(matches!(
+      &lexemes[1].0,
+      HtmlElement::Text(Cow::Borrowed("text"))
+    ));
+    assert!(matches!(
+      &lexemes[2].0,
+      HtmlElement::Comment(Cow::Borrowed("c"))
+    ));
+    assert!(matches!(
+      &lexemes[3].0,
+      HtmlElement::Script {
+        contents: Cow::Borrowed("s"),
+        ..
+      }
+    ));
+    let owned = lexemes[0].0.clone().into_owned();
+    let borrowed = owned.as_borrowed();
+    assert!(matches!(
+      borrowed,
+      HtmlElement::OpenTag {
+        name: Cow::Borrowed("img"),
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn records_spans() {
+    let file: Rc<Path> = Path::new("page.html").into();
+    let source = "<p>\n  h\u{e9}llo <b>x</b></p>";
+    let lexemes =
+      parse_html_from(source, ParseMode::Strict, Some(file.clone())).unwrap();
+    let spans: Vec<_> = lexemes
+      .iter()
+      .map(|(_, span)| (span.start, span.end, span.line, span.column))
+      .collect();
+    // The `é` takes two bytes but one column
+    assert_eq!(
+      spans,
+      [
+        (0, 3, 1, 1),
+        (3, 13, 1, 4),
+        (13, 16, 2, 9),
+        (16, 17, 2, 12),
+        (17, 21, 2, 13),
+        (21, 25, 2, 17)
+      ]
+    );
+    assert_eq!(lexemes[2].1.file.as_ref(), Some(&file));
+    assert_eq!(lexemes[2].1.to_string(), "page.html:2:9");
+  }
+
+  #[test]
+  fn gives_implied_close_tags_empty_spans() {
+    let lexemes =
+      parse_html_with_mode("<ul><li>a<li>b</ul>", ParseMode::Tolerant).unwrap();
+    let (lm, span) = &lexemes[3];
+    assert!(matches!(lm, HtmlElement::CloseTag { name } if name == "li"));
+    assert_eq!((span.start, span.end, span.column), (9, 9, 10));
+  }
+
+  #[test]
+  fn round_trips_attribute_values() {
+    for value in [
+      "",
+      "plain",
+      r#"quotes " and '"#,
+      "a & b &amp; &eacute; &#39;",
+      "&",
+      "&&;",
+      "日本語 😀",
+      "@@literal",
+    ] {
+      round_trip(value);
+    }
   }
 }
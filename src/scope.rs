@@ -0,0 +1,153 @@
+use std::{collections::HashMap, path::Path, rc::Rc};
+
+use crate::{
+  compile::Templates,
+  parse::{HtmlElement, Span},
+  trace::*,
+  tree::*,
+};
+
+/// Qualifies `name` with `namespace`, as in `icons:Rust`
+pub fn qualify(namespace: Option<&str>, name: &str) -> String {
+  match namespace {
+    Some(namespace) => format!("{}:{}", namespace, name),
+    None => name.to_string(),
+  }
+}
+
+/// A `<@import from="icons.html" as="icons" />` directive, which
+/// makes the templates of another file usable as `<icons:Name />`
+#[derive(Clone, Debug)]
+pub struct Import {
+  /// The imported file, relative to the importing one
+  pub from: String,
+  pub alias: String,
+  pub span: Span,
+}
+
+impl Import {
+  /// Reads an import from the attributes of an `@import`
+  /// directive
+  pub fn parse(lm: &HtmlElement<'_>, span: &Span) -> Result<Option<Self>> {
+    let HtmlElement::Directive {
+      name, attributes, ..
+    } = lm
+    else {
+      return Ok(None);
+    };
+    if name != "import" {
+      return Ok(None);
+    }
+    let attribute = |key: &str| {
+      attributes
+        .get(key)
+        .map(|value| value.to_string())
+        .ok_or_else(|| {
+          compile_error(format!("`<@import>` needs a `{}` attribute", key))
+            .at(span)
+        })
+    };
+    Ok(Some(Self {
+      from: attribute("from")?,
+      alias: attribute("as")?,
+      span: span.clone(),
+    }))
+  }
+
+  /// The imported file, found relative to the directory of the
+  /// file the import is in
+  pub fn path(&self) -> Rc<Path> {
+    match self.span.file.as_deref().and_then(Path::parent) {
+      Some(dir) => dir.join(&self.from).into(),
+      None => Path::new(&self.from).into(),
+    }
+  }
+}
+
+/// Every import anywhere in `nodes`
+pub fn find_imports(nodes: &[Node<'_>]) -> Result<Vec<Import>> {
+  let mut imports = vec![];
+  for node in descendants(nodes) {
+    if let Node::Leaf(lm, span) = node {
+      imports.extend(Import::parse(lm, span)?);
+    }
+  }
+  Ok(imports)
+}
+
+/// How template names used in one file are resolved
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+  /// The namespace of the templates the file defines, which they
+  /// can use from each other without qualifying
+  pub namespace: Option<String>,
+  /// The namespaces imported files were loaded under, by alias.
+  /// Files loaded without a namespace have their templates shared
+  pub aliases: HashMap<String, Option<String>>,
+}
+
+/// The scope of each file, by path
+pub type Scopes = HashMap<Rc<Path>, Scope>;
+
+/// Finds the template that `name` refers to in a file with
+/// `scope`. Names qualified by an imported alias only look in the
+/// imported file; other names look in the file's own namespace
+/// before the shared one
+pub fn resolve<'t, 'a>(
+  templates: &'t Templates<'a>,
+  scope: Option<&Scope>,
+  name: &str,
+) -> Option<(&'t String, &'t Element<'a>)> {
+  if let Some(scope) = scope {
+    let imported = name
+      .split_once(':')
+      .and_then(|(alias, name)| Some((scope.aliases.get(alias)?, name)));
+    if let Some((namespace, name)) = imported {
+      return templates.get_key_value(&qualify(namespace.as_deref(), name));
+    }
+    if let Some(namespace) = &scope.namespace {
+      let found = templates.get_key_value(&qualify(Some(namespace), name));
+      if found.is_some() {
+        return found;
+      }
+    }
+  }
+  templates.get_key_value(name)
+}
+
+/// Whether an element that matches no template was probably
+/// meant to use one, because its name is capitalized like a
+/// template's rather than lowercase like HTML
+pub fn looks_like_component(name: &str) -> bool {
+  let local = name.rsplit(':').next().unwrap_or(name);
+  local.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+/// Every name that resolves to a template in a file with `scope`
+pub fn visible_names(
+  templates: &Templates<'_>,
+  scope: Option<&Scope>,
+) -> Vec<String> {
+  let mut names = vec![];
+  for name in templates.keys() {
+    names.push(name.clone());
+    let Some(scope) = scope else {
+      continue;
+    };
+    if let Some(namespace) = &scope.namespace {
+      if let Some(local) = name.strip_prefix(&format!("{}:", namespace)) {
+        names.push(local.to_string());
+      }
+    }
+    for (alias, namespace) in &scope.aliases {
+      let local = match namespace {
+        Some(namespace) => name.strip_prefix(&format!("{}:", namespace)),
+        None => Some(name.as_str()),
+      };
+      if let Some(local) = local {
+        names.push(qualify(Some(alias), local));
+      }
+    }
+  }
+  names
+}
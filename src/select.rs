@@ -0,0 +1,471 @@
+use std::str::FromStr;
+
+use crate::{
+  parse::Attributes,
+  trace::{Error, ErrorKind, Result},
+  tree::*,
+};
+
+/// How an attribute selector compares against the attribute value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttributeOp {
+  /// `[name]`
+  Exists,
+  /// `[name=value]`
+  Equals,
+  /// `[name~=value]`, one of the whitespace separated words
+  Includes,
+  /// `[name|=value]`, exactly `value` or starting with `value-`
+  DashMatch,
+  /// `[name^=value]`
+  Prefix,
+  /// `[name$=value]`
+  Suffix,
+  /// `[name*=value]`
+  Substring,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeSelector {
+  pub name: String,
+  pub op: AttributeOp,
+  pub value: String,
+}
+
+/// A sequence of simple selectors that all apply to the same
+/// element, such as `a.external[target=_blank]`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Compound {
+  /// The tag name, or `None` for `*` or when left out
+  pub name: Option<String>,
+  pub ids: Vec<String>,
+  pub classes: Vec<String>,
+  pub attributes: Vec<AttributeSelector>,
+}
+
+/// The relationship between a compound and the one to its left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combinator {
+  /// `a b`, any ancestor
+  Descendant,
+  /// `a > b`, the direct parent
+  Child,
+}
+
+/// Compounds joined by combinators, such as `nav > ul a`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Complex {
+  /// The rightmost compound, which the element itself matches
+  pub subject: Compound,
+  /// The compounds to the left of the subject, nearest first
+  pub ancestors: Vec<(Combinator, Compound)>,
+}
+
+/// A comma separated list of selectors, matching elements that
+/// match any of them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selector {
+  pub alternatives: Vec<Complex>,
+}
+
+fn selector_error(source: &str, reason: &str) -> Error {
+  Error::new(
+    ErrorKind::Parsing,
+    format!("invalid selector `{}`: {}", source, reason),
+  )
+}
+
+fn is_ident_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '-' || c == '_' || c == '@'
+}
+
+fn parse_ident(i: &str) -> (&str, &str) {
+  let end = i.find(|c| !is_ident_char(c)).unwrap_or(i.len());
+  (&i[..end], &i[end..])
+}
+
+fn parse_attribute_selector(i: &str) -> Option<(AttributeSelector, &str)> {
+  let i = i.strip_prefix('[')?.trim_start();
+  let end = i.find(|c: char| c.is_whitespace() || "~|^$*=]".contains(c))?;
+  let (name, i) = (&i[..end], i[end..].trim_start());
+  if name.is_empty() {
+    return None;
+  }
+  if let Some(i) = i.strip_prefix(']') {
+    let selector = AttributeSelector {
+      name: name.to_string(),
+      op: AttributeOp::Exists,
+      value: String::new(),
+    };
+    return Some((selector, i));
+  }
+  let ops = [
+    ("=", AttributeOp::Equals),
+    ("~=", AttributeOp::Includes),
+    ("|=", AttributeOp::DashMatch),
+    ("^=", AttributeOp::Prefix),
+    ("$=", AttributeOp::Suffix),
+    ("*=", AttributeOp::Substring),
+  ];
+  let (op, i) = ops
+    .into_iter()
+    .find_map(|(token, op)| Some((op, i.strip_prefix(token)?)))?;
+  let i = i.trim_start();
+  let (value, i) = match i.chars().next()? {
+    quote @ ('"' | '\'') => {
+      let end = i[1..].find(quote)? + 1;
+      (&i[1..end], &i[end + 1..])
+    },
+    _ => {
+      let end = i.find(|c: char| c.is_whitespace() || c == ']')?;
+      (&i[..end], &i[end..])
+    },
+  };
+  let i = i.trim_start().strip_prefix(']')?;
+  let selector = AttributeSelector {
+    name: name.to_string(),
+    op,
+    value: value.to_string(),
+  };
+  Some((selector, i))
+}
+
+fn parse_compound(mut i: &str) -> Option<(Compound, &str)> {
+  let mut compound = Compound::default();
+  let start = i.len();
+  if let Some(tail) = i.strip_prefix('*') {
+    i = tail;
+  } else {
+    let (name, tail) = parse_ident(i);
+    if !name.is_empty() {
+      compound.name = Some(name.to_ascii_lowercase());
+      i = tail;
+    }
+  }
+  loop {
+    if let Some(tail) = i.strip_prefix('#') {
+      let (id, tail) = parse_ident(tail);
+      compound.ids.push(id.to_string());
+      i = tail;
+    } else if let Some(tail) = i.strip_prefix('.') {
+      let (class, tail) = parse_ident(tail);
+      compound.classes.push(class.to_string());
+      i = tail;
+    } else if i.starts_with('[') {
+      let (attribute, tail) = parse_attribute_selector(i)?;
+      compound.attributes.push(attribute);
+      i = tail;
+    } else {
+      break;
+    }
+  }
+  let empty_part = compound
+    .ids
+    .iter()
+    .chain(&compound.classes)
+    .any(String::is_empty);
+  if i.len() == start || empty_part {
+    return None;
+  }
+  Some((compound, i))
+}
+
+fn parse_complex(source: &str) -> Result<Complex> {
+  let mut compounds = vec![];
+  let mut combinators = vec![];
+  let mut i = source.trim();
+  let unsupported = || {
+    selector_error(source, "pseudo-classes such as `:hover` are not supported")
+  };
+  loop {
+    if i.starts_with(':') {
+      return Err(unsupported());
+    }
+    let (compound, tail) = parse_compound(i).ok_or_else(|| {
+      selector_error(source, "expected a tag, class, id or attribute")
+    })?;
+    compounds.push(compound);
+    if tail.starts_with(':') {
+      return Err(unsupported());
+    }
+    let trimmed = tail.trim_start();
+    if trimmed.is_empty() {
+      break;
+    }
+    if let Some(tail) = trimmed.strip_prefix('>') {
+      combinators.push(Combinator::Child);
+      i = tail.trim_start();
+    } else if trimmed.len() < tail.len() {
+      combinators.push(Combinator::Descendant);
+      i = trimmed;
+    } else {
+      return Err(selector_error(source, "unexpected character"));
+    }
+  }
+  let subject = compounds.pop().unwrap();
+  let ancestors = combinators
+    .into_iter()
+    .rev()
+    .zip(compounds.into_iter().rev())
+    .collect();
+  Ok(Complex { subject, ancestors })
+}
+
+/// Splits a selector list on the commas between its selectors,
+/// skipping commas inside of attribute selectors such as
+/// `[title="a,b"]`
+fn split_list(source: &str) -> Vec<&str> {
+  let mut parts = vec![];
+  let mut start = 0;
+  let mut quote = None;
+  let mut in_brackets = false;
+  for (index, c) in source.char_indices() {
+    match (quote, c) {
+      (Some(open), c) if c == open => quote = None,
+      (Some(_), _) => {},
+      (None, '"' | '\'') if in_brackets => quote = Some(c),
+      (None, '[') => in_brackets = true,
+      (None, ']') => in_brackets = false,
+      (None, ',') if !in_brackets => {
+        parts.push(&source[start..index]);
+        start = index + 1;
+      },
+      _ => {},
+    }
+  }
+  parts.push(&source[start..]);
+  parts
+}
+
+impl Selector {
+  pub fn parse(source: &str) -> Result<Self> {
+    let alternatives = split_list(source)
+      .into_iter()
+      .map(parse_complex)
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Self { alternatives })
+  }
+
+  /// Whether `element` matches, given its `ancestors` from the
+  /// root down to its parent
+  pub fn matches(
+    &self,
+    element: &Element<'_>,
+    ancestors: &[&Element<'_>],
+  ) -> bool {
+    self.matches_tag(&element.name, &element.attributes, ancestors)
+  }
+
+  /// Whether a tag with `name` and `attributes` matches, which
+  /// may be a leaf such as `<script>` rather than an element
+  pub fn matches_tag(
+    &self,
+    name: &str,
+    attributes: &Attributes<'_>,
+    ancestors: &[&Element<'_>],
+  ) -> bool {
+    self
+      .alternatives
+      .iter()
+      .any(|complex| complex.matches_tag(name, attributes, ancestors))
+  }
+}
+
+impl FromStr for Selector {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Self::parse(s)
+  }
+}
+
+impl AttributeSelector {
+  fn matches(&self, attributes: &Attributes<'_>) -> bool {
+    let Some(actual) = attributes.get(self.name.as_str()) else {
+      return false;
+    };
+    let value = self.value.as_str();
+    match self.op {
+      AttributeOp::Exists => true,
+      AttributeOp::Equals => actual == value,
+      AttributeOp::Includes => {
+        actual.split_whitespace().any(|word| word == value)
+      },
+      AttributeOp::DashMatch => {
+        actual == value
+          || actual
+            .strip_prefix(value)
+            .is_some_and(|tail| tail.starts_with('-'))
+      },
+      AttributeOp::Prefix => !value.is_empty() && actual.starts_with(value),
+      AttributeOp::Suffix => !value.is_empty() && actual.ends_with(value),
+      AttributeOp::Substring => !value.is_empty() && actual.contains(value),
+    }
+  }
+}
+
+impl Compound {
+  pub fn matches(&self, element: &Element<'_>) -> bool {
+    self.matches_tag(&element.name, &element.attributes)
+  }
+
+  pub fn matches_tag(&self, name: &str, attributes: &Attributes<'_>) -> bool {
+    let name_matches = self
+      .name
+      .as_ref()
+      .is_none_or(|own| own.eq_ignore_ascii_case(name));
+    let get = |key: &str| attributes.get(key).map(|value| value.as_ref());
+    let classes = get("class").unwrap_or_default();
+    name_matches
+      && self.ids.iter().all(|id| get("id") == Some(id.as_str()))
+      && self
+        .classes
+        .iter()
+        .all(|class| classes.split_whitespace().any(|c| c == class))
+      && self
+        .attributes
+        .iter()
+        .all(|attribute| attribute.matches(attributes))
+  }
+}
+
+impl Complex {
+  pub fn matches(
+    &self,
+    element: &Element<'_>,
+    ancestors: &[&Element<'_>],
+  ) -> bool {
+    self.matches_tag(&element.name, &element.attributes, ancestors)
+  }
+
+  pub fn matches_tag(
+    &self,
+    name: &str,
+    attributes: &Attributes<'_>,
+    ancestors: &[&Element<'_>],
+  ) -> bool {
+    self.subject.matches_tag(name, attributes)
+      && matches_ancestors(&self.ancestors, ancestors)
+  }
+}
+
+/// Matches the remaining compounds, nearest first, against the
+/// remaining ancestors, root first, backtracking over
+/// descendant combinators
+fn matches_ancestors(
+  compounds: &[(Combinator, Compound)],
+  ancestors: &[&Element<'_>],
+) -> bool {
+  let Some(((combinator, compound), compounds)) = compounds.split_first()
+  else {
+    return true;
+  };
+  match combinator {
+    Combinator::Child => match ancestors.split_last() {
+      Some((parent, ancestors)) => {
+        compound.matches(parent) && matches_ancestors(compounds, ancestors)
+      },
+      None => false,
+    },
+    Combinator::Descendant => (0..ancestors.len()).rev().any(|index| {
+      compound.matches(ancestors[index])
+        && matches_ancestors(compounds, &ancestors[..index])
+    }),
+  }
+}
+
+/// Collects the path and node of every match in `nodes` or
+/// below, including leaves such as `<script>` that have a tag
+fn select_into<'n, 'a>(
+  nodes: &'n [Node<'a>],
+  selector: &Selector,
+  ancestors: &mut Vec<&'n Element<'a>>,
+  path: &mut Vec<usize>,
+  output: &mut Vec<(Vec<usize>, &'n Node<'a>)>,
+) {
+  for (index, node) in nodes.iter().enumerate() {
+    let Some((name, attributes)) = node.tag() else {
+      continue;
+    };
+    path.push(index);
+    if selector.matches_tag(name, attributes, ancestors) {
+      output.push((path.clone(), node));
+    }
+    if let Node::Element(element) = node {
+      ancestors.push(element);
+      select_into(&element.children, selector, ancestors, path, output);
+      ancestors.pop();
+    }
+    path.pop();
+  }
+}
+
+/// Every element in `nodes` or below matching `selector`, in
+/// document order. Leaves such as `<script>` are not elements,
+/// so are left out
+pub fn select<'n, 'a>(
+  nodes: &'n [Node<'a>],
+  selector: &Selector,
+) -> Vec<&'n Element<'a>> {
+  let mut output = vec![];
+  select_into(nodes, selector, &mut vec![], &mut vec![], &mut output);
+  output
+    .into_iter()
+    .filter_map(|(_, node)| node.as_element())
+    .collect()
+}
+
+fn node_at_path<'n, 'a>(
+  nodes: &'n mut [Node<'a>],
+  path: &[usize],
+) -> Option<&'n mut Node<'a>> {
+  let (first, rest) = path.split_first()?;
+  let node = nodes.get_mut(*first)?;
+  if rest.is_empty() {
+    Some(node)
+  } else {
+    node_at_path(&mut node.as_element_mut()?.children, rest)
+  }
+}
+
+/// Paths to every match in `nodes` or below, in document order
+fn select_paths(nodes: &[Node<'_>], selector: &Selector) -> Vec<Vec<usize>> {
+  let mut matches = vec![];
+  select_into(nodes, selector, &mut vec![], &mut vec![], &mut matches);
+  matches.into_iter().map(|(path, _)| path).collect()
+}
+
+/// Calls `visit` on every element in `nodes` or below matching
+/// `selector`. Elements are matched before any are visited, and
+/// visited in reverse document order so that changing an element
+/// never moves one that is yet to be visited
+pub fn select_mut<'a>(
+  nodes: &mut [Node<'a>],
+  selector: &Selector,
+  mut visit: impl FnMut(&mut Element<'a>),
+) {
+  for path in select_paths(nodes, selector).iter().rev() {
+    if let Some(element) =
+      node_at_path(nodes, path).and_then(Node::as_element_mut)
+    {
+      visit(element);
+    }
+  }
+}
+
+/// Calls `visit` on the attributes of every node in `nodes` or
+/// below matching `selector`, including leaves such as
+/// `<script>` and `<textarea>`
+pub fn select_attributes_mut<'a>(
+  nodes: &mut [Node<'a>],
+  selector: &Selector,
+  mut visit: impl FnMut(&mut Attributes<'a>),
+) {
+  for path in select_paths(nodes, selector).iter().rev() {
+    if let Some(attributes) =
+      node_at_path(nodes, path).and_then(Node::attributes_mut)
+    {
+      visit(attributes);
+    }
+  }
+}
@@ -1,11 +1,50 @@
 use std::{fmt::Display, path::Path};
 
+use crate::parse::Span;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Error {
   pub kind: ErrorKind,
   pub reason: String,
-  pub backtrace: Vec<String>,
+  /// Where in the source the error was found, if known
+  pub span: Option<Span>,
+  /// Context added while the error was passed up, innermost
+  /// first
+  pub backtrace: Vec<Note>,
+}
+
+/// A line of context attached to an error, such as the template
+/// usage being expanded when it happened
+#[derive(Clone, Debug)]
+pub struct Note {
+  pub message: String,
+  pub span: Option<Span>,
+}
+
+impl Note {
+  pub fn new(message: impl Into<String>) -> Self {
+    Self {
+      message: message.into(),
+      span: None,
+    }
+  }
+
+  pub fn at(message: impl Into<String>, span: &Span) -> Self {
+    Self {
+      message: message.into(),
+      span: Some(span.clone()),
+    }
+  }
+}
+
+impl Display for Note {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.span {
+      Some(span) => write!(f, "{} at {}", self.message, span),
+      None => write!(f, "{}", self.message),
+    }
+  }
 }
 
 impl Error {
@@ -13,10 +52,24 @@ impl Error {
     Self {
       kind,
       reason: reason.into(),
+      span: None,
       backtrace: vec![],
     }
   }
 
+  /// Points the error at `span`, unless it already points
+  /// somewhere more specific
+  pub fn at(mut self, span: &Span) -> Self {
+    self.span.get_or_insert_with(|| span.clone());
+    self
+  }
+
+  /// Adds context pointing at the place that led to the error
+  pub fn note_at(mut self, message: impl Into<String>, span: &Span) -> Self {
+    self.backtrace.push(Note::at(message, span));
+    self
+  }
+
   pub fn msg(mut self, message: impl Into<String>) -> Self {
     self.reason = message.into();
     self
@@ -27,6 +80,7 @@ pub fn compile_error(reason: impl Into<String>) -> Error {
   Error {
     kind: ErrorKind::Compilation,
     reason: reason.into(),
+    span: None,
     backtrace: vec![],
   }
 }
@@ -63,9 +117,13 @@ impl Display for ErrorKind {
 
 impl Display for Error {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "\n[{} ERROR] {}\nBacktrace:\n", self.kind, self.reason)?;
+    writeln!(f, "\n[{} ERROR] {}", self.kind, self.reason)?;
+    if let Some(span) = &self.span {
+      writeln!(f, "at {}", span)?;
+    }
+    writeln!(f, "Backtrace:")?;
     for s in self.backtrace.iter().rev() {
-      write!(f, "{}\n", s)?;
+      writeln!(f, "{}", s)?;
     }
     Ok(())
   }
@@ -82,6 +140,7 @@ impl From<std::io::Error> for Error {
     Self {
       kind: ErrorKind::IO,
       reason: format!("{}", value),
+      span: None,
       backtrace: vec![],
     }
   }
@@ -98,7 +157,7 @@ where
 {
   fn ctx(self, s: S) -> Result<T> {
     self.map_err(|e| e.into()).map_err(|mut e| {
-      e.backtrace.push(s.into());
+      e.backtrace.push(Note::new(s));
       e
     })
   }
@@ -112,7 +171,8 @@ where
     self.ok_or_else(|| Error {
       kind: ErrorKind::Unknown,
       reason: "Missing expected value".into(),
-      backtrace: vec![s.into()],
+      span: None,
+      backtrace: vec![Note::new(s)],
     })
   }
 }
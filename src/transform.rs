@@ -0,0 +1,228 @@
+use std::path::Path;
+
+use crate::{
+  parse::{ordered_attributes, Attributes, ParseMode},
+  select::*,
+  trace::*,
+  tree::*,
+};
+
+/// A change made to the attributes of every tag matched by a
+/// transform, including raw text ones such as `<script>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+  /// Sets an attribute, replacing any previous value
+  Set(String, String),
+  /// Adds a word to a whitespace separated attribute such as
+  /// `rel` or `class`, unless it is already present
+  Append(String, String),
+  /// Removes an attribute
+  Remove(String),
+}
+
+impl Action {
+  pub fn apply(&self, element: &mut Element<'_>) {
+    self.apply_to(&mut element.attributes);
+  }
+
+  /// Applies the action to the attributes of any tag, including
+  /// leaves such as `<script>`
+  pub fn apply_to(&self, attributes: &mut Attributes<'_>) {
+    match self {
+      Action::Set(key, value) => {
+        attributes.insert(key.clone().into(), value.clone().into());
+      },
+      Action::Append(key, value) => {
+        let new_value = match attributes.get(key.as_str()) {
+          Some(old) if old.split_whitespace().any(|word| word == value) => {
+            return
+          },
+          Some(old) if !old.trim().is_empty() => {
+            format!("{} {}", old.trim_end(), value)
+          },
+          _ => value.clone(),
+        };
+        attributes.insert(key.clone().into(), new_value.into());
+      },
+      Action::Remove(key) => {
+        attributes.remove(key.as_str());
+      },
+    }
+  }
+}
+
+/// Post-processing applied to compiled pages, such as adding
+/// `loading="lazy"` to every `img`
+#[derive(Clone, Debug)]
+pub struct Transform {
+  pub selector: Selector,
+  pub actions: Vec<Action>,
+}
+
+impl Transform {
+  pub fn new(selector: Selector) -> Self {
+    Self {
+      selector,
+      actions: vec![],
+    }
+  }
+
+  pub fn set(
+    mut self,
+    key: impl Into<String>,
+    value: impl Into<String>,
+  ) -> Self {
+    self.actions.push(Action::Set(key.into(), value.into()));
+    self
+  }
+
+  pub fn append(
+    mut self,
+    key: impl Into<String>,
+    value: impl Into<String>,
+  ) -> Self {
+    self.actions.push(Action::Append(key.into(), value.into()));
+    self
+  }
+
+  pub fn remove(mut self, key: impl Into<String>) -> Self {
+    self.actions.push(Action::Remove(key.into()));
+    self
+  }
+
+  pub fn apply(&self, nodes: &mut [Node<'_>]) {
+    select_attributes_mut(nodes, &self.selector, |attributes| {
+      for action in &self.actions {
+        action.apply_to(attributes);
+      }
+    });
+  }
+}
+
+/// Reads transforms declared as `<transform>` tags, where
+/// `select` is a CSS selector and every other attribute is an
+/// action:
+///
+/// ```html
+/// <transform select="img" set:loading="lazy" />
+/// <transform select="a[target=_blank][href^=http]"
+///   append:rel="noopener" />
+/// <transform select="script[async]" remove:async />
+/// ```
+///
+/// Actions are applied in the order they are written
+pub fn parse_transforms(source: &str) -> Result<Vec<Transform>> {
+  let mut transforms = vec![];
+  for element in find_all(&parse_document(source, ParseMode::Strict)?, |e| {
+    e.name == "transform"
+  }) {
+    let selector = element
+      .get_attribute("select")
+      .ctx("transform is missing a `select` attribute")?;
+    let mut transform = Transform::new(Selector::parse(selector)?);
+    let tag = &source[element.span.start..element.span.end];
+    let attributes = ordered_attributes(tag).unwrap_or_default();
+    for (key, value) in &attributes {
+      transform = if let Some(key) = key.strip_prefix("set:") {
+        transform.set(key, value.as_ref())
+      } else if let Some(key) = key.strip_prefix("append:") {
+        transform.append(key, value.as_ref())
+      } else if let Some(key) = key.strip_prefix("remove:") {
+        transform.remove(key)
+      } else if key == "select" {
+        transform
+      } else {
+        return Err(Error::new(
+          ErrorKind::Parsing,
+          format!("unknown transform action `{}`", key),
+        ));
+      };
+    }
+    transforms.push(transform);
+  }
+  Ok(transforms)
+}
+
+pub fn parse_transforms_file(path: impl AsRef<Path>) -> Result<Vec<Transform>> {
+  let file = read_file(path.as_ref()).ctx("opening transforms file")?;
+  parse_transforms(&file).ctx(format!("in file {}", path.as_ref().display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn transform(transforms: &str, source: &str) -> String {
+    let mut nodes = parse_document(source, ParseMode::Strict).unwrap();
+    for transform in parse_transforms(transforms).unwrap() {
+      transform.apply(&mut nodes);
+    }
+    serialize(&nodes)
+  }
+
+  #[test]
+  fn transforms_raw_text_leaves() {
+    let output = transform(
+      r#"<transform select="script[async]" remove:async />"#,
+      r#"<script async src="a.js"></script>"#,
+    );
+    assert_eq!(output, "<script src=\"a.js\">\n\n</script>");
+    let output = transform(
+      r#"<transform select="form textarea" set:rows="4" />"#,
+      "<form><textarea>x</textarea></form><textarea>y</textarea>",
+    );
+    assert_eq!(
+      output,
+      "<form><textarea rows=\"4\">x</textarea></form><textarea>y</textarea>"
+    );
+  }
+
+  #[test]
+  fn splits_selector_lists_on_top_level_commas() {
+    let selector = Selector::parse(r#"a[title="a,b"], img"#).unwrap();
+    assert_eq!(selector.alternatives.len(), 2);
+    let source = r#"<a title="a,b"></a><a title="a"></a><img/>"#;
+    let output = transform(
+      r#"<transform select="a[title='a,b'],img" set:x="1" />"#,
+      source,
+    );
+    let nodes = parse_document(&output, ParseMode::Strict).unwrap();
+    let marked: Vec<_> = select(&nodes, &Selector::parse("[x]").unwrap())
+      .into_iter()
+      .map(|element| (element.name.as_ref(), element.get_attribute("title")))
+      .collect();
+    assert_eq!(marked, [("a", Some("a,b")), ("img", None)]);
+  }
+
+  #[test]
+  fn rejects_pseudo_classes() {
+    for source in ["a:hover", ":first-child", "nav > li:not(.x)", "a, b::after"]
+    {
+      let error = Selector::parse(source).unwrap_err();
+      assert!(error.reason.contains("pseudo-classes"), "{}", error.reason);
+    }
+    assert!(Selector::parse("a[x-on:click]").is_ok());
+  }
+
+  #[test]
+  fn applies_actions_in_source_order() {
+    let source = r#"<a rel="x"></a>"#;
+    let output = transform(
+      r#"<transform select="a" append:rel="y" set:rel="z" append:rel="w" />"#,
+      source,
+    );
+    assert_eq!(output, r#"<a rel="z w"></a>"#);
+    let output = transform(
+      r#"<transform select="a" set:rel="z" append:rel="y" remove:rel />"#,
+      source,
+    );
+    assert_eq!(output, "<a></a>");
+    for _ in 0..8 {
+      let output = transform(
+        r#"<transform select="a" remove:rel set:rel="n" append:rel="m" />"#,
+        source,
+      );
+      assert_eq!(output, r#"<a rel="n m"></a>"#);
+    }
+  }
+}
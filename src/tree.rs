@@ -0,0 +1,483 @@
+use std::{borrow::Cow, path::Path, rc::Rc};
+
+use crate::{directives::expand_directive, parse::*};
+
+/// A tag and everything between it and its close tag
+#[derive(Clone, Debug)]
+pub struct Element<'a> {
+  /// The name of the tag
+  pub name: Cow<'a, str>,
+  /// Attribute names and their values if present
+  pub attributes: Attributes<'a>,
+  /// Whether the tag closes itself, in which case it never has
+  /// children
+  pub is_empty: bool,
+  pub children: Vec<Node<'a>>,
+  /// Where the open tag was found
+  pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum Node<'a> {
+  Element(Element<'a>),
+  /// Any lexeme that is not an open or close tag, such as text,
+  /// comments, scripts and directives
+  Leaf(HtmlElement<'a>, Span),
+}
+
+impl<'a> Element<'a> {
+  pub fn new(name: impl Into<Cow<'a, str>>) -> Self {
+    Self {
+      name: name.into(),
+      attributes: Default::default(),
+      is_empty: false,
+      children: vec![],
+      span: Span::default(),
+    }
+  }
+
+  pub fn get_attribute(&self, key: &str) -> Option<&str> {
+    self.attributes.get(key).map(|value| value.as_ref())
+  }
+
+  pub fn has_attribute(&self, key: &str) -> bool {
+    self.attributes.contains_key(key)
+  }
+
+  pub fn set_attribute(
+    &mut self,
+    key: impl Into<Cow<'a, str>>,
+    value: impl Into<Cow<'a, str>>,
+  ) -> &mut Self {
+    self.attributes.insert(key.into(), value.into());
+    self
+  }
+
+  pub fn remove_attribute(&mut self, key: &str) -> Option<Cow<'a, str>> {
+    self.attributes.remove(key)
+  }
+
+  /// Every node below this element, depth first
+  pub fn descendants(&self) -> Descendants<'_, 'a> {
+    descendants(&self.children)
+  }
+
+  /// The first element below this one matching `predicate`
+  pub fn find(
+    &self,
+    predicate: impl FnMut(&Element<'a>) -> bool,
+  ) -> Option<&Element<'a>> {
+    find(&self.children, predicate)
+  }
+
+  /// Every element below this one matching `predicate`
+  pub fn find_all(
+    &self,
+    predicate: impl FnMut(&Element<'a>) -> bool,
+  ) -> Vec<&Element<'a>> {
+    find_all(&self.children, predicate)
+  }
+
+  /// The concatenated text of every text node below this one
+  pub fn text(&self) -> String {
+    self
+      .descendants()
+      .filter_map(|node| match node {
+        Node::Leaf(HtmlElement::Text(text), _) => Some(text.as_ref()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  /// A copy of this element that borrows all of its text from
+  /// `self` instead of allocating
+  pub fn as_borrowed(&self) -> Element<'_> {
+    Element {
+      name: Cow::Borrowed(&self.name),
+      attributes: borrow_attributes(&self.attributes),
+      is_empty: self.is_empty,
+      children: self.children.iter().map(Node::as_borrowed).collect(),
+      span: self.span.clone(),
+    }
+  }
+
+  /// Copies any borrowed text so the element can outlive the
+  /// source it was parsed from
+  pub fn into_owned(self) -> Element<'static> {
+    Element {
+      name: Cow::Owned(self.name.into_owned()),
+      attributes: own_attributes(self.attributes),
+      is_empty: self.is_empty,
+      children: self.children.into_iter().map(Node::into_owned).collect(),
+      span: self.span,
+    }
+  }
+
+  pub fn serialize(&self) -> String {
+    let mut output = String::new();
+    self.serialize_into(&mut output);
+    output
+  }
+
+  fn serialize_into(&self, output: &mut String) {
+    // Directives with HTML bodies stay elements named `@name`
+    // until now, so that transforms can reach inside of them
+    if let Some(name) = self.name.strip_prefix('@') {
+      let contents = serialize(&self.children);
+      let directive = expand_directive(name, &self.attributes, &contents);
+      output.push_str(&directive);
+      return;
+    }
+    let attributes = serialize_attributes(&self.attributes);
+    if self.is_empty {
+      output.push_str(&format!("<{}{}/>", self.name, attributes));
+      return;
+    }
+    output.push_str(&format!("<{}{}>", self.name, attributes));
+    for child in &self.children {
+      child.serialize_into(output);
+    }
+    output.push_str(&format!("</{}>", self.name));
+  }
+}
+
+impl<'a> Node<'a> {
+  pub fn as_element(&self) -> Option<&Element<'a>> {
+    match self {
+      Node::Element(element) => Some(element),
+      Node::Leaf(..) => None,
+    }
+  }
+
+  pub fn as_element_mut(&mut self) -> Option<&mut Element<'a>> {
+    match self {
+      Node::Element(element) => Some(element),
+      Node::Leaf(..) => None,
+    }
+  }
+
+  /// The tag name and attributes of an element, or of a leaf
+  /// whose contents are raw text, such as `<script>` or
+  /// `<textarea>`
+  pub fn tag(&self) -> Option<(&str, &Attributes<'a>)> {
+    match self {
+      Node::Element(element) => Some((&element.name, &element.attributes)),
+      Node::Leaf(HtmlElement::Script { attributes, .. }, _) => {
+        Some(("script", attributes))
+      },
+      Node::Leaf(HtmlElement::Style { attributes, .. }, _) => {
+        Some(("style", attributes))
+      },
+      Node::Leaf(
+        HtmlElement::RawText {
+          name, attributes, ..
+        },
+        _,
+      ) => Some((name, attributes)),
+      Node::Leaf(..) => None,
+    }
+  }
+
+  /// The attributes of any node that has a `tag`
+  pub fn attributes_mut(&mut self) -> Option<&mut Attributes<'a>> {
+    match self {
+      Node::Element(Element { attributes, .. })
+      | Node::Leaf(HtmlElement::Script { attributes, .. }, _)
+      | Node::Leaf(HtmlElement::Style { attributes, .. }, _)
+      | Node::Leaf(HtmlElement::RawText { attributes, .. }, _) => {
+        Some(attributes)
+      },
+      Node::Leaf(..) => None,
+    }
+  }
+
+  /// Where the node was found, which for an element is its open
+  /// tag
+  pub fn span(&self) -> &Span {
+    match self {
+      Node::Element(element) => &element.span,
+      Node::Leaf(_, span) => span,
+    }
+  }
+
+  pub fn as_borrowed(&self) -> Node<'_> {
+    match self {
+      Node::Element(element) => Node::Element(element.as_borrowed()),
+      Node::Leaf(lm, span) => Node::Leaf(lm.as_borrowed(), span.clone()),
+    }
+  }
+
+  pub fn into_owned(self) -> Node<'static> {
+    match self {
+      Node::Element(element) => Node::Element(element.into_owned()),
+      Node::Leaf(lm, span) => Node::Leaf(lm.into_owned(), span),
+    }
+  }
+
+  /// The number of nodes in this subtree, including itself
+  pub fn size(&self) -> usize {
+    match self {
+      Node::Element(element) => 1 + element.descendants().count(),
+      Node::Leaf(..) => 1,
+    }
+  }
+
+  pub fn serialize(&self) -> String {
+    let mut output = String::new();
+    self.serialize_into(&mut output);
+    output
+  }
+
+  fn serialize_into(&self, output: &mut String) {
+    match self {
+      Node::Element(element) => element.serialize_into(output),
+      Node::Leaf(lm, _) => output.push_str(&lm.serialize()),
+    }
+  }
+}
+
+/// Depth first iterator over a forest of nodes, created by
+/// `descendants`
+pub struct Descendants<'n, 'a> {
+  stack: Vec<std::slice::Iter<'n, Node<'a>>>,
+}
+
+impl<'n, 'a> Iterator for Descendants<'n, 'a> {
+  type Item = &'n Node<'a>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      let node = match self.stack.last_mut()?.next() {
+        Some(node) => node,
+        None => {
+          self.stack.pop();
+          continue;
+        },
+      };
+      if let Node::Element(element) = node {
+        self.stack.push(element.children.iter());
+      }
+      return Some(node);
+    }
+  }
+}
+
+/// Every node in `nodes` and below, depth first
+pub fn descendants<'n, 'a>(nodes: &'n [Node<'a>]) -> Descendants<'n, 'a> {
+  Descendants {
+    stack: vec![nodes.iter()],
+  }
+}
+
+/// The first element in `nodes` or below matching `predicate`
+pub fn find<'n, 'a>(
+  nodes: &'n [Node<'a>],
+  mut predicate: impl FnMut(&Element<'a>) -> bool,
+) -> Option<&'n Element<'a>> {
+  descendants(nodes)
+    .filter_map(Node::as_element)
+    .find(|element| predicate(element))
+}
+
+/// Every element in `nodes` or below matching `predicate`
+pub fn find_all<'n, 'a>(
+  nodes: &'n [Node<'a>],
+  mut predicate: impl FnMut(&Element<'a>) -> bool,
+) -> Vec<&'n Element<'a>> {
+  descendants(nodes)
+    .filter_map(Node::as_element)
+    .filter(|element| predicate(element))
+    .collect()
+}
+
+/// Calls `visit` on every element in `nodes` and below, parents
+/// before their children, allowing them to be modified
+pub fn walk_mut<'a>(
+  nodes: &mut [Node<'a>],
+  visit: &mut impl FnMut(&mut Element<'a>),
+) {
+  for node in nodes {
+    if let Node::Element(element) = node {
+      visit(element);
+      walk_mut(&mut element.children, visit);
+    }
+  }
+}
+
+/// Removes every node in `nodes` and below for which `keep`
+/// returns false
+pub fn retain<'a>(
+  nodes: &mut Vec<Node<'a>>,
+  keep: &mut impl FnMut(&Node<'a>) -> bool,
+) {
+  nodes.retain(|node| keep(node));
+  for node in nodes {
+    if let Node::Element(element) = node {
+      retain(&mut element.children, keep);
+    }
+  }
+}
+
+pub fn serialize(nodes: &[Node<'_>]) -> String {
+  let mut output = String::new();
+  for node in nodes {
+    node.serialize_into(&mut output);
+  }
+  output
+}
+
+/// Nests a balanced sequence of lexemes into a tree. Close tags
+/// without a matching open tag are dropped, and any tags left
+/// open are closed at the end
+pub fn build_tree<'a>(
+  lexemes: impl IntoIterator<Item = (HtmlElement<'a>, Span)>,
+) -> Vec<Node<'a>> {
+  let mut roots = vec![];
+  let mut stack: Vec<Element<'a>> = vec![];
+  let push =
+    |node, stack: &mut Vec<Element<'a>>, roots: &mut Vec<Node<'a>>| match stack
+      .last_mut()
+    {
+      Some(parent) => parent.children.push(node),
+      None => roots.push(node),
+    };
+  for (lm, span) in lexemes {
+    match lm {
+      HtmlElement::OpenTag {
+        name,
+        attributes,
+        is_empty,
+      } => {
+        let element = Element {
+          name,
+          attributes,
+          is_empty,
+          children: vec![],
+          span,
+        };
+        if is_empty {
+          push(Node::Element(element), &mut stack, &mut roots);
+        } else {
+          stack.push(element);
+        }
+      },
+      HtmlElement::CloseTag { .. } => {
+        if let Some(element) = stack.pop() {
+          push(Node::Element(element), &mut stack, &mut roots);
+        }
+      },
+      lm => push(Node::Leaf(lm, span), &mut stack, &mut roots),
+    }
+  }
+  while let Some(element) = stack.pop() {
+    push(Node::Element(element), &mut stack, &mut roots);
+  }
+  roots
+}
+
+pub fn parse_document(
+  input: &str,
+  mode: ParseMode,
+) -> Result<Vec<Node<'_>>, crate::trace::Error> {
+  parse_document_from(input, mode, None)
+}
+
+/// Parses `input` into a tree, naming `file` as the source of
+/// every node's span
+pub fn parse_document_from(
+  input: &str,
+  mode: ParseMode,
+  file: Option<Rc<Path>>,
+) -> Result<Vec<Node<'_>>, crate::trace::Error> {
+  Ok(build_tree(parse_html_from(input, mode, file)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn document(source: &str) -> Vec<Node<'_>> {
+    parse_document(source, ParseMode::Strict).unwrap()
+  }
+
+  /// The tag name of every element, or the text of every leaf
+  fn names<'n>(nodes: impl IntoIterator<Item = &'n Node<'n>>) -> Vec<String> {
+    nodes
+      .into_iter()
+      .map(|node| match node {
+        Node::Element(element) => element.name.to_string(),
+        Node::Leaf(lm, _) => lm.serialize(),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn builds_nested_elements() {
+    let nodes = document("<div><p>a<br>b</p><img/></div>c");
+    assert_eq!(names(&nodes), ["div", "c"]);
+    let div = nodes[0].as_element().unwrap();
+    assert_eq!(names(&div.children), ["p", "img"]);
+    let p = div.children[0].as_element().unwrap();
+    assert_eq!(names(&p.children), ["a", "br", "b"]);
+    assert!(p.children[1].as_element().unwrap().is_empty);
+    assert_eq!(serialize(&nodes), "<div><p>a<br/>b</p><img/></div>c");
+  }
+
+  #[test]
+  fn closes_unbalanced_lexemes() {
+    let lexemes = parse_html("<div><p>a</p></div>").unwrap();
+    // Drop the close tags of `p` and `div`, and add a stray one
+    let mut lexemes: Vec<_> = lexemes
+      .into_iter()
+      .filter(|(lm, _)| !matches!(lm, HtmlElement::CloseTag { .. }))
+      .collect();
+    lexemes.insert(
+      0,
+      (HtmlElement::CloseTag { name: "b".into() }, Span::default()),
+    );
+    let nodes = build_tree(lexemes);
+    assert_eq!(serialize(&nodes), "<div><p>a</p></div>");
+  }
+
+  #[test]
+  fn walks_descendants_depth_first() {
+    let nodes = document("<a><b>1</b><c><d/></c></a><e/>");
+    assert_eq!(names(descendants(&nodes)), ["a", "b", "1", "c", "d", "e"]);
+    let a = nodes[0].as_element().unwrap();
+    assert_eq!(names(a.descendants()), ["b", "1", "c", "d"]);
+    assert_eq!(nodes[0].size(), 5);
+    assert_eq!(a.text(), "1");
+    assert_eq!(find(&nodes, |e| e.children.is_empty()).unwrap().name, "d");
+    let empty: Vec<_> = find_all(&nodes, |e| e.is_empty)
+      .iter()
+      .map(|e| e.name.to_string())
+      .collect();
+    assert_eq!(empty, ["d", "e"]);
+  }
+
+  #[test]
+  fn visits_parents_before_children() {
+    let mut nodes = document("<a><b><c/></b></a><d/>");
+    let mut visited = vec![];
+    walk_mut(&mut nodes, &mut |element| {
+      visited.push(element.name.to_string());
+      element.set_attribute("seen", "");
+    });
+    assert_eq!(visited, ["a", "b", "c", "d"]);
+    assert_eq!(
+      serialize(&nodes),
+      "<a seen><b seen><c seen/></b></a><d seen/>"
+    );
+  }
+
+  #[test]
+  fn retains_nodes_at_every_depth() {
+    let mut nodes = document("<a>x<b>y<i/></b><i/></a><i/>z");
+    retain(&mut nodes, &mut |node| {
+      node.as_element().is_none_or(|element| element.name != "i")
+    });
+    assert_eq!(serialize(&nodes), "<a>x<b>y</b></a>z");
+    retain(&mut nodes, &mut |node| node.as_element().is_some());
+    assert_eq!(serialize(&nodes), "<a><b></b></a>");
+  }
+}